@@ -0,0 +1,182 @@
+//! Append-only archive of messages truncated from the chat context.
+//!
+//! Each line of the archive is a single JSON object,
+//! so appending never needs to rewrite what is already stored.
+
+use std::fs;
+use std::io;
+use std::io::BufRead;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::path::Path;
+
+use async_openai::types::ChatCompletionRequestMessage;
+use color_eyre::eyre;
+use color_eyre::eyre::Context;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::embedding::PackedEmbedding;
+use crate::history::now;
+use crate::history::ChatEntry;
+use crate::history::MessageMetadata;
+
+/// A message removed from the chat context,
+/// together with the time (in seconds since the Unix epoch) it was archived.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ArchivedMessage {
+    pub timestamp: u64,
+    pub message: ChatCompletionRequestMessage,
+    pub embedding: PackedEmbedding,
+    #[serde(default)]
+    pub metadata: MessageMetadata,
+}
+
+#[inline]
+pub fn append_to_archive(chat: &[ChatEntry], path: impl AsRef<Path>) -> eyre::Result<()> {
+    let path = path.as_ref();
+    let timestamp = now()?;
+
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .read(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("failed to open {}", path.display()))?;
+    truncate_torn_record(&mut file)
+        .with_context(|| format!("failed to repair {}", path.display()))?;
+    let mut writer = io::BufWriter::new(file);
+    for entry in chat {
+        let record = ArchivedMessage {
+            timestamp,
+            message: entry.message.clone(),
+            embedding: entry.embedding.clone(),
+            metadata: entry.metadata.clone(),
+        };
+        serde_json::to_writer(&mut writer, &record)
+            .with_context(|| format!("failed to serialize message to {}", path.display()))?;
+        writeln!(writer).with_context(|| format!("failed to write to {}", path.display()))?;
+    }
+    writer
+        .into_inner()
+        .map_err(io::IntoInnerError::into_error)
+        .and_then(|file| file.sync_all())
+        .with_context(|| format!("failed to sync {}", path.display()))?;
+
+    Ok(())
+}
+
+#[inline]
+pub fn read_archive(path: impl AsRef<Path>) -> eyre::Result<Vec<ArchivedMessage>> {
+    let path = path.as_ref();
+    if !path
+        .try_exists()
+        .context("failed to check if chat archive exists")?
+    {
+        return Ok(Vec::new());
+    }
+
+    let file =
+        fs::File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+    let lines = io::BufReader::new(file)
+        .split(b'\n')
+        .collect::<io::Result<Vec<_>>>()
+        .with_context(|| format!("failed to read from {}", path.display()))?;
+    let last = lines.iter().rposition(|line| !line.trim_ascii().is_empty());
+    let mut archive = Vec::new();
+    for (n, line) in lines.iter().enumerate() {
+        if line.trim_ascii().is_empty() {
+            continue;
+        }
+        match serde_json::from_slice(line) {
+            Ok(record) => archive.push(record),
+            // Its messages are still in the chat history,
+            // which is only overwritten after archiving succeeds.
+            Err(error) if Some(n) == last => eprintln!(
+                "warning: ignoring half-written line {} of {}: {error}",
+                n + 1,
+                path.display()
+            ),
+            Err(error) => {
+                return Err(error).with_context(|| {
+                    format!("failed to deserialize line {} of {}", n + 1, path.display())
+                })
+            }
+        }
+    }
+    Ok(archive)
+}
+
+/// Drop what an interrupted append left after the last complete line,
+/// so that the next record starts on a line of its own.
+#[inline]
+fn truncate_torn_record(file: &mut fs::File) -> io::Result<()> {
+    let len = file.metadata()?.len();
+    let mut end = len;
+    let mut buffer = [0; 4096];
+    while end > 0 {
+        let start = end.saturating_sub(buffer.len() as u64);
+        let chunk = &mut buffer[..(end - start) as usize];
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(chunk)?;
+        if let Some(newline) = chunk.iter().rposition(|&byte| byte == b'\n') {
+            end = start + newline as u64 + 1;
+            break;
+        }
+        end = start;
+    }
+    if end < len {
+        file.set_len(end)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use async_openai::types::Role;
+
+    use super::*;
+    use crate::history::test_entry;
+
+    #[test]
+    fn archive_roundtrip_works() -> eyre::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("archive.jsonl");
+        assert!(read_archive(&path)?.is_empty());
+
+        append_to_archive(&[test_entry(Role::User, "Hello", vec![1.0, 0.0])], &path)?;
+        append_to_archive(&[test_entry(Role::User, "Hello", vec![0.0, 1.0])], &path)?;
+
+        let archive = read_archive(&path)?;
+        assert_eq!(archive.len(), 2);
+        assert_eq!(archive[0].message.content, "Hello");
+        assert_eq!(archive[1].embedding.unpack()?, [0.0, 1.0]);
+        Ok(())
+    }
+
+    #[test]
+    fn archive_survives_interrupted_appends() -> eyre::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("archive.jsonl");
+        append_to_archive(&[test_entry(Role::User, "Hello", vec![1.0])], &path)?;
+        let complete = fs::read(&path)?;
+
+        let mut torn = complete.clone();
+        torn.extend_from_slice(&complete[..complete.len() / 2]);
+        fs::write(&path, &torn)?;
+        assert_eq!(read_archive(&path)?.len(), 1);
+
+        append_to_archive(&[test_entry(Role::User, "Hello", vec![0.0])], &path)?;
+        let archive = read_archive(&path)?;
+        assert_eq!(archive.len(), 2);
+        assert_eq!(archive[1].embedding.unpack()?, [0.0]);
+
+        torn.push(b'\n');
+        torn.extend_from_slice(&complete);
+        fs::write(&path, &torn)?;
+        assert!(read_archive(&path).is_err());
+        Ok(())
+    }
+}
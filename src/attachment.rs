@@ -0,0 +1,234 @@
+//! Context attached to messages,
+//! such as input piped along with an instruction and files.
+
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+
+use color_eyre::eyre;
+use color_eyre::eyre::Context;
+
+use crate::strip_trailing_newline;
+
+/// Largest file attached, in bytes.
+const MAX_FILE_SIZE: u64 = 100 * 1024;
+/// Largest size of all files attached to a message together, in bytes.
+const MAX_TOTAL_SIZE: u64 = 400 * 1024;
+/// Number of leading bytes searched for signs of a binary file.
+const BINARY_CHECK_LENGTH: usize = 8000;
+
+/// `content` as a Markdown fenced code block,
+/// with `info` (e.g., a language) after the opening fence.
+///
+/// The fence is longer than any backtick fence inside `content`,
+/// so that the block can't be closed early.
+#[inline]
+pub fn fenced_block(content: &str, info: &str) -> String {
+    let longest_fence = content
+        .lines()
+        .map(|line| line.trim_start().chars().take_while(|&c| c == '`').count())
+        .max()
+        .unwrap_or(0);
+    let fence = "`".repeat(longest_fence.max(2) + 1);
+    let content = strip_trailing_newline(content);
+    format!("{fence}{info}\n{content}\n{fence}")
+}
+
+/// `instruction` followed by `input` as a fenced code block,
+/// unless there is no input.
+#[inline]
+pub fn with_input(instruction: &str, input: &str) -> String {
+    if input.trim().is_empty() {
+        instruction.into()
+    } else {
+        format!("{instruction}\n\n{}", fenced_block(input, ""))
+    }
+}
+
+/// Files matching any of `patterns` (paths or globs),
+/// each as a fenced code block labelled with its path.
+///
+/// Files too large, binary or not valid UTF-8 are skipped with a warning,
+/// but attaching too much altogether is an error.
+#[inline]
+pub fn attach_files(patterns: &[String]) -> eyre::Result<Vec<String>> {
+    attach_files_within(patterns, MAX_FILE_SIZE, MAX_TOTAL_SIZE)
+}
+
+#[inline]
+fn attach_files_within(
+    patterns: &[String],
+    max_file_size: u64,
+    max_total_size: u64,
+) -> eyre::Result<Vec<String>> {
+    let mut paths = Vec::new();
+    for pattern in patterns {
+        for path in expand_pattern(pattern)? {
+            if !paths.contains(&path) {
+                paths.push(path);
+            }
+        }
+    }
+
+    let mut attached = Vec::new();
+    let mut total_size = 0;
+    for path in paths {
+        let size = fs::metadata(&path)
+            .with_context(|| format!("failed to read {}", path.display()))?
+            .len();
+        if size > max_file_size {
+            eprintln!(
+                "warning: skipping {} (larger than {max_file_size} bytes)",
+                path.display()
+            );
+            continue;
+        }
+        let contents =
+            fs::read(&path).with_context(|| format!("failed to read {}", path.display()))?;
+        let contents = match text(contents) {
+            Ok(contents) => contents,
+            Err(reason) => {
+                eprintln!("warning: skipping {} ({reason})", path.display());
+                continue;
+            }
+        };
+
+        total_size += size;
+        eyre::ensure!(
+            total_size <= max_total_size,
+            "attached files are larger than {max_total_size} bytes altogether"
+        );
+        attached.push(format!(
+            "`{}`:\n{}",
+            path.display(),
+            fenced_block(&contents, language(&path))
+        ));
+    }
+    Ok(attached)
+}
+
+/// The files `pattern` stands for,
+/// which is either a path or a glob.
+#[inline]
+fn expand_pattern(pattern: &str) -> eyre::Result<Vec<PathBuf>> {
+    if !pattern.contains(['*', '?', '[']) {
+        let path = PathBuf::from(pattern);
+        eyre::ensure!(path.is_file(), "'{pattern}' is not a file");
+        return Ok(vec![path]);
+    }
+
+    let mut paths = Vec::new();
+    for path in glob::glob(pattern).with_context(|| format!("invalid glob '{pattern}'"))? {
+        let path = path.with_context(|| format!("failed to expand glob '{pattern}'"))?;
+        if path.is_file() {
+            paths.push(path);
+        }
+    }
+    eyre::ensure!(!paths.is_empty(), "no files match '{pattern}'");
+    Ok(paths)
+}
+
+/// `contents` as text,
+/// or why it can't be attached as such.
+#[inline]
+fn text(contents: Vec<u8>) -> Result<String, &'static str> {
+    // Same heuristic as Git's.
+    let head = &contents[..contents.len().min(BINARY_CHECK_LENGTH)];
+    if head.contains(&0) {
+        return Err("binary file");
+    }
+    String::from_utf8(contents).map_err(|_| "not valid UTF-8")
+}
+
+/// Language of the file at `path` as named in Markdown code blocks,
+/// guessed from its extension.
+#[inline]
+fn language(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or_default();
+    match extension.to_lowercase().as_str() {
+        "c" | "h" => "c",
+        "cc" | "cpp" | "cxx" | "hpp" => "cpp",
+        "cs" => "csharp",
+        "css" => "css",
+        "go" => "go",
+        "hs" => "haskell",
+        "html" | "htm" => "html",
+        "java" => "java",
+        "js" | "mjs" | "cjs" => "javascript",
+        "json" => "json",
+        "jl" => "julia",
+        "kt" | "kts" => "kotlin",
+        "lua" => "lua",
+        "md" => "markdown",
+        "php" => "php",
+        "py" => "python",
+        "rb" => "ruby",
+        "rs" => "rust",
+        "scala" => "scala",
+        "sh" | "bash" | "zsh" => "bash",
+        "sql" => "sql",
+        "swift" => "swift",
+        "toml" => "toml",
+        "ts" | "tsx" => "typescript",
+        "xml" => "xml",
+        "yaml" | "yml" => "yaml",
+        _ => "",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fenced_block_works() {
+        assert_eq!(
+            fenced_block("fn main() {}\n", "rust"),
+            "```rust\nfn main() {}\n```"
+        );
+        assert_eq!(
+            fenced_block("````\nnested\n````", ""),
+            "`````\n````\nnested\n````\n`````"
+        );
+        assert_eq!(with_input("explain", " \n"), "explain");
+        assert_eq!(
+            with_input("explain", "x = 1\n"),
+            "explain\n\n```\nx = 1\n```"
+        );
+    }
+
+    #[test]
+    fn text_works() {
+        assert_eq!(text(b"fn main() {}".to_vec()).unwrap(), "fn main() {}");
+        assert_eq!(text(b"\0ELF".to_vec()), Err("binary file"));
+        assert_eq!(text(b"caf\xE9".to_vec()), Err("not valid UTF-8"));
+    }
+
+    #[test]
+    fn attach_files_works() -> eyre::Result<()> {
+        let dir = tempfile::tempdir()?;
+        fs::write(dir.path().join("a.rs"), "fn a() {}\n")?;
+        fs::write(dir.path().join("b.rs"), "fn b() {}\n")?;
+        fs::write(dir.path().join("big.rs"), "x".repeat(100))?;
+        fs::write(dir.path().join("binary.rs"), b"\0ELF")?;
+        fs::write(dir.path().join("latin1.rs"), b"// caf\xE9\n")?;
+        fs::write(dir.path().join("notes.txt"), "notes")?;
+        let a = dir.path().join("a.rs").display().to_string();
+        let all = dir.path().join("*.rs").display().to_string();
+        let none = dir.path().join("*.py").display().to_string();
+        let directory = dir.path().display().to_string();
+
+        let attached = attach_files_within(&[a.clone(), all.clone()], 50, 1000)?;
+        assert_eq!(attached.len(), 2);
+        assert_eq!(attached[0], format!("`{a}`:\n```rust\nfn a() {{}}\n```"));
+        assert!(attached[1].contains("fn b() {}"));
+
+        assert!(attach_files_within(&[all], 50, 15).is_err());
+        assert!(attach_files_within(&[none], 50, 1000).is_err());
+        assert!(attach_files_within(&[directory], 50, 1000).is_err());
+        Ok(())
+    }
+}
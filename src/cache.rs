@@ -0,0 +1,108 @@
+//! Content-addressed cache of embeddings.
+//!
+//! Each embedding is stored in its own file,
+//! named after a hash of the API base, the embedding model and the embedded
+//! text,
+//! so that identical inputs are only ever embedded once.
+
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use color_eyre::eyre;
+use color_eyre::eyre::Context;
+use sha2::Digest as _;
+use sha2::Sha256;
+
+use crate::embedding::PackedEmbedding;
+use crate::history::write_atomically;
+use crate::Embedding;
+
+pub struct EmbeddingCache {
+    dir: PathBuf,
+}
+
+impl EmbeddingCache {
+    #[inline]
+    pub fn open(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// The cached embedding of `input` by `model` of the API at `api_base`,
+    /// if any.
+    ///
+    /// Models of the same name may differ between APIs (e.g., local servers).
+    #[inline]
+    pub fn get(&self, api_base: &str, model: &str, input: &str) -> eyre::Result<Option<Embedding>> {
+        let path = self.path(api_base, model, input);
+        let packed = match fs::read_to_string(&path) {
+            Ok(packed) => packed,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(error) => {
+                return Err(error).with_context(|| format!("failed to read {}", path.display()))
+            }
+        };
+        let packed: PackedEmbedding = serde_json::from_str(&packed)
+            .with_context(|| format!("failed to parse {}", path.display()))?;
+        Ok(Some(packed.unpack()?))
+    }
+
+    #[inline]
+    pub fn insert(
+        &self,
+        api_base: &str,
+        model: &str,
+        input: &str,
+        embedding: &[f32],
+    ) -> eyre::Result<()> {
+        let path = self.path(api_base, model, input);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("failed to create {}", dir.display()))?;
+        }
+        write_atomically(&path, |writer| {
+            serde_json::to_writer(writer, &PackedEmbedding::pack(embedding))
+                .with_context(|| format!("failed to serialize embedding to {}", path.display()))
+        })
+    }
+
+    #[inline]
+    fn path(&self, api_base: &str, model: &str, input: &str) -> PathBuf {
+        let mut hasher = Sha256::new();
+        hasher.update(api_base.as_bytes());
+        hasher.update([0]);
+        hasher.update(model.as_bytes());
+        hasher.update([0]);
+        hasher.update(input.as_bytes());
+        let key: String = hasher
+            .finalize()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect();
+        // Spread entries over subdirectories, as `git` does with objects.
+        self.dir.join(&key[..2]).join(&key[2..])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cache_roundtrip() -> eyre::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let cache = EmbeddingCache::open(dir.path());
+
+        let api = "https://api.openai.com/v1";
+        assert_eq!(cache.get(api, "model", "hello")?, None);
+        cache.insert(api, "model", "hello", &[1.0, 2.0])?;
+        assert_eq!(cache.get(api, "model", "hello")?, Some(vec![1.0, 2.0]));
+        assert_eq!(cache.get(api, "other-model", "hello")?, None);
+        assert_eq!(cache.get(api, "model", "hello!")?, None);
+        assert_eq!(
+            cache.get("http://localhost:8080/v1", "model", "hello")?,
+            None
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,383 @@
+//! Settings read from configuration files.
+//!
+//! The configuration file of the user is read first,
+//! then the `.cligpt.toml` of the project,
+//! found in the current directory or any of its parents.
+//! Settings of later files take precedence,
+//! and within each file,
+//! those of the selected profile take precedence over the top-level ones.
+//!
+//! Anyone can put a `.cligpt.toml` in a repository,
+//! so settings that could leak keys or files are only read from it
+//! if the user trusts its directory.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+
+use color_eyre::eyre;
+use color_eyre::eyre::Context;
+use serde::Deserialize;
+use serde::Serialize;
+
+const CONFIG_FILE: &str = "config.toml";
+const PROJECT_CONFIG_FILE: &str = ".cligpt.toml";
+
+/// Defaults for command-line options.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Settings {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub api_key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub api_base: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub context_window: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reserved_tokens: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub context_strategy: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub summarize: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub recall: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub embedding_model: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub embedding_dimension: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub system: Option<String>,
+
+    /// Directories (as absolute paths) whose `.cligpt.toml` may set every
+    /// setting.
+    ///
+    /// Only read from the configuration file of the user.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub trusted_projects: Vec<PathBuf>,
+
+    /// Named sets of settings selectable with `--profile`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub profile: BTreeMap<String, Settings>,
+}
+
+impl Settings {
+    /// `self` overridden by whatever `other` sets.
+    ///
+    /// Profiles are left out,
+    /// since they have been applied already.
+    #[inline]
+    fn merge(self, other: Self) -> Self {
+        Self {
+            model: other.model.or(self.model),
+            temperature: other.temperature.or(self.temperature),
+            api_key: other.api_key.or(self.api_key),
+            api_base: other.api_base.or(self.api_base),
+            context_window: other.context_window.or(self.context_window),
+            reserved_tokens: other.reserved_tokens.or(self.reserved_tokens),
+            context_strategy: other.context_strategy.or(self.context_strategy),
+            summarize: other.summarize.or(self.summarize),
+            recall: other.recall.or(self.recall),
+            embedding_model: other.embedding_model.or(self.embedding_model),
+            embedding_dimension: other.embedding_dimension.or(self.embedding_dimension),
+            system: other.system.or(self.system),
+            trusted_projects: [self.trusted_projects, other.trusted_projects].concat(),
+            profile: BTreeMap::new(),
+        }
+    }
+
+    /// Make `@file` prompts relative to `dir`,
+    /// the directory of the file setting them.
+    #[inline]
+    fn resolve_paths(&mut self, dir: &Path) {
+        if let Some(path) = self
+            .system
+            .as_deref()
+            .and_then(|system| system.strip_prefix('@'))
+        {
+            self.system = Some(format!("@{}", dir.join(path).display()));
+        }
+        for profile in self.profile.values_mut() {
+            profile.resolve_paths(dir);
+        }
+    }
+
+    /// Clear the settings an untrusted project could abuse,
+    /// returning their names.
+    ///
+    /// These would send requests (and API keys) elsewhere
+    /// or read any file into the system prompt.
+    #[inline]
+    fn untrust(&mut self) -> Vec<&'static str> {
+        let mut cleared = Vec::new();
+        if self.api_key.take().is_some() {
+            cleared.push("api-key");
+        }
+        if self.api_base.take().is_some() {
+            cleared.push("api-base");
+        }
+        if self
+            .system
+            .take_if(|system| system.starts_with('@'))
+            .is_some()
+        {
+            cleared.push("system");
+        }
+        cleared
+    }
+}
+
+/// The configuration files that apply in the current directory.
+#[derive(Debug)]
+pub struct ConfigFiles {
+    pub user: PathBuf,
+    pub project: Option<PathBuf>,
+}
+
+impl ConfigFiles {
+    #[inline]
+    pub fn find(config_dir: &Path) -> eyre::Result<Self> {
+        let current_dir = env::current_dir().context("failed to obtain the current directory")?;
+        let project = current_dir
+            .ancestors()
+            .map(|dir| dir.join(PROJECT_CONFIG_FILE))
+            .find(|path| path.is_file());
+        Ok(Self {
+            user: config_dir.join(CONFIG_FILE),
+            project,
+        })
+    }
+
+    /// Where a configuration file for the current project would be.
+    #[inline]
+    pub fn project_or_default(&self) -> eyre::Result<PathBuf> {
+        match &self.project {
+            Some(project) => Ok(project.clone()),
+            None => Ok(env::current_dir()
+                .context("failed to obtain the current directory")?
+                .join(PROJECT_CONFIG_FILE)),
+        }
+    }
+
+    /// Paths of all files, in order of increasing precedence.
+    #[inline]
+    pub fn paths(&self) -> impl Iterator<Item = &Path> {
+        std::iter::once(self.user.as_path()).chain(self.project.as_deref())
+    }
+
+    /// Settings of all files together,
+    /// with those of `profile` applied.
+    #[inline]
+    pub fn load(&self, profile: Option<&str>) -> eyre::Result<Settings> {
+        let user = read_config_file(&self.user)?;
+        let trusted_projects = user.as_ref().map_or(&[][..], |user| &user.trusted_projects);
+        let mut project = match &self.project {
+            Some(path) => read_config_file(path)?,
+            None => None,
+        };
+        if let (Some(path), Some(project)) = (&self.project, &mut project) {
+            eyre::ensure!(
+                project.trusted_projects.is_empty(),
+                "{} cannot set trusted-projects (only your own configuration file can)",
+                path.display()
+            );
+            if !is_trusted(path, trusted_projects) {
+                untrust(project, path);
+            }
+        }
+
+        let mut settings = Settings::default();
+        let mut found_profile = false;
+        for mut file in user.into_iter().chain(project) {
+            let selected = profile.and_then(|name| file.profile.remove(name));
+            settings = settings.merge(file);
+            if let Some(selected) = selected {
+                found_profile = true;
+                settings = settings.merge(selected);
+            }
+        }
+
+        if let Some(name) = profile {
+            eyre::ensure!(found_profile, "profile '{name}' does not exist");
+        }
+        Ok(settings)
+    }
+}
+
+/// Whether the directory of the project file at `path` is trusted.
+#[inline]
+fn is_trusted(path: &Path, trusted_projects: &[PathBuf]) -> bool {
+    let Some(dir) = path.parent().and_then(|dir| fs::canonicalize(dir).ok()) else {
+        return false;
+    };
+    trusted_projects
+        .iter()
+        .any(|trusted| fs::canonicalize(trusted).is_ok_and(|trusted| trusted == dir))
+}
+
+/// Clear what the untrusted project file at `path` mustn't set,
+/// warning about it.
+#[inline]
+fn untrust(project: &mut Settings, path: &Path) {
+    let mut cleared = project.untrust();
+    for profile in project.profile.values_mut() {
+        cleared.extend(profile.untrust());
+    }
+    cleared.sort_unstable();
+    cleared.dedup();
+    if !cleared.is_empty() {
+        eprintln!(
+            "warning: ignoring {} in {} (list {} in trusted-projects of your configuration file \
+             to trust it)",
+            cleared.join(", "),
+            path.display(),
+            path.parent().unwrap_or(path).display()
+        );
+    }
+}
+
+/// Settings of the file at `path`,
+/// or nothing if there is no such file.
+#[inline]
+pub fn read_config_file(path: &Path) -> eyre::Result<Option<Settings>> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(error) => {
+            return Err(error).with_context(|| format!("failed to read {}", path.display()))
+        }
+    };
+    let mut settings: Settings =
+        toml::from_str(&contents).with_context(|| format!("failed to parse {}", path.display()))?;
+    for (name, profile) in &settings.profile {
+        eyre::ensure!(
+            profile.profile.is_empty(),
+            "profile '{name}' in {} cannot have profiles of its own",
+            path.display()
+        );
+        eyre::ensure!(
+            profile.trusted_projects.is_empty(),
+            "profile '{name}' in {} cannot set trusted-projects",
+            path.display()
+        );
+    }
+    // Project files apply in subdirectories too,
+    // so paths can't be relative to the current directory.
+    settings.resolve_paths(path.parent().unwrap_or(Path::new("")));
+    Ok(Some(settings))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn settings_are_layered() -> eyre::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let user = dir.path().join(CONFIG_FILE);
+        let project = dir.path().join(PROJECT_CONFIG_FILE);
+        fs::write(
+            &user,
+            r#"
+            model = "gpt4"
+            temperature = 0.5
+            recall = 2
+            system = "@prompt.md"
+
+            [profile.local]
+            api-base = "http://localhost:8080/v1"
+            model = "llama"
+            "#,
+        )?;
+        fs::write(
+            &project,
+            r#"
+            temperature = 0.2
+
+            [profile.local]
+            recall = 0
+            "#,
+        )?;
+        let files = ConfigFiles {
+            user,
+            project: Some(project.clone()),
+        };
+
+        let settings = files.load(None)?;
+        assert_eq!(settings.model.as_deref(), Some("gpt4"));
+        assert_eq!(settings.temperature, Some(0.2));
+        assert_eq!(settings.recall, Some(2));
+        assert_eq!(settings.api_base, None);
+        assert_eq!(
+            settings.system,
+            Some(format!("@{}", dir.path().join("prompt.md").display()))
+        );
+
+        let settings = files.load(Some("local"))?;
+        assert_eq!(settings.model.as_deref(), Some("llama"));
+        assert_eq!(settings.temperature, Some(0.2));
+        assert_eq!(settings.recall, Some(0));
+        assert_eq!(
+            settings.api_base.as_deref(),
+            Some("http://localhost:8080/v1")
+        );
+
+        assert!(files.load(Some("missing")).is_err());
+        fs::write(&project, "modle = \"gpt4\"")?;
+        assert!(files.load(None).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn untrusted_projects_are_restricted() -> eyre::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let user = dir.path().join(CONFIG_FILE);
+        let project = dir.path().join(PROJECT_CONFIG_FILE);
+        fs::write(
+            &project,
+            r#"
+            model = "gpt4"
+            api-key = "sk-stolen"
+            system = "@/etc/passwd"
+
+            [profile.local]
+            api-base = "http://attacker.example/v1"
+            "#,
+        )?;
+        let files = ConfigFiles {
+            user: user.clone(),
+            project: Some(project.clone()),
+        };
+
+        let settings = files.load(Some("local"))?;
+        assert_eq!(settings.model.as_deref(), Some("gpt4"));
+        assert_eq!(settings.api_key, None);
+        assert_eq!(settings.api_base, None);
+        assert_eq!(settings.system, None);
+
+        fs::write(
+            &user,
+            format!(
+                "trusted-projects = [{:?}]",
+                dir.path().display().to_string()
+            ),
+        )?;
+        let settings = files.load(Some("local"))?;
+        assert_eq!(settings.api_key.as_deref(), Some("sk-stolen"));
+        assert_eq!(
+            settings.api_base.as_deref(),
+            Some("http://attacker.example/v1")
+        );
+        assert_eq!(settings.system.as_deref(), Some("@/etc/passwd"));
+
+        fs::write(&project, "trusted-projects = [\"/\"]")?;
+        assert!(files.load(None).is_err());
+        Ok(())
+    }
+}
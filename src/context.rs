@@ -0,0 +1,569 @@
+//! Strategies for deciding which messages stay in the chat context.
+
+use std::fmt;
+
+use async_openai::types::Role;
+use color_eyre::eyre;
+use color_eyre::eyre::Context;
+
+use crate::cosine_similarity;
+use crate::history::ChatEntry;
+use crate::tokens;
+use crate::tokens::TokenBudget;
+
+/// Messages to keep in the chat context,
+/// and the outdated ones removed from it (if any).
+pub type Split = (Vec<ChatEntry>, Option<Vec<ChatEntry>>);
+
+/// A way of deciding which messages stay in the chat context.
+pub trait ContextStrategy {
+    /// Split a chat into the messages to keep and the outdated ones.
+    ///
+    /// Outdated messages always come before the ones kept,
+    /// except for pinned messages kept by [`KeepPinned`].
+    fn split(&self, chat: Vec<ChatEntry>) -> eyre::Result<Split>;
+}
+
+/// Context strategies selectable from the command line.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Strategy {
+    /// Truncate at the message least similar to the last exchange,
+    /// but only if it comes before the most similar one.
+    #[default]
+    Similarity,
+
+    /// Keep only the given number of most recent messages,
+    /// or so, since replies go together with their questions.
+    SlidingWindow(usize),
+
+    /// Keep as many recent messages as fit in the context window.
+    TokenBudget,
+
+    /// Like [`Strategy::TokenBudget`],
+    /// but condense the messages truncated into a running summary.
+    Summarize,
+
+    /// Never truncate the chat.
+    None,
+}
+
+/// See [`Strategy::Similarity`].
+#[derive(Clone, Copy, Debug)]
+pub struct Similarity;
+
+/// See [`Strategy::SlidingWindow`].
+#[derive(Clone, Copy, Debug)]
+pub struct SlidingWindow(pub usize);
+
+/// See [`Strategy::None`].
+#[derive(Clone, Copy, Debug)]
+pub struct NoTruncation;
+
+/// Keeps the pinned messages another strategy would truncate.
+#[derive(Clone, Copy, Debug)]
+pub struct KeepPinned<S>(pub S);
+
+impl Strategy {
+    /// Build the strategy,
+    /// using `budget` for the ones that need to count tokens.
+    ///
+    /// Pinned messages are always kept.
+    #[inline]
+    pub fn build<'a>(self, budget: &'a TokenBudget) -> Box<dyn ContextStrategy + 'a> {
+        match self {
+            Self::Similarity => Box::new(KeepPinned(Similarity)),
+            Self::SlidingWindow(size) => Box::new(KeepPinned(SlidingWindow(size))),
+            Self::TokenBudget | Self::Summarize => Box::new(KeepPinned(budget)),
+            Self::None => Box::new(NoTruncation),
+        }
+    }
+
+    /// Whether messages truncated are condensed into the summary.
+    #[inline]
+    pub fn summarizes(self) -> bool {
+        self == Self::Summarize
+    }
+}
+
+impl fmt::Display for Strategy {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Similarity => write!(f, "similarity"),
+            Self::SlidingWindow(size) => write!(f, "sliding-window:{size}"),
+            Self::TokenBudget => write!(f, "token-budget"),
+            Self::Summarize => write!(f, "summarize"),
+            Self::None => write!(f, "none"),
+        }
+    }
+}
+
+#[inline]
+pub fn strategy_parser(strategy: &str) -> eyre::Result<Strategy> {
+    let (name, argument) = match strategy.split_once(':') {
+        Some((name, argument)) => (name, Some(argument)),
+        None => (strategy, None),
+    };
+
+    let strategy = match (name, argument) {
+        ("similarity", None) => Strategy::Similarity,
+        ("sliding-window", Some(size)) => {
+            let size: usize = size
+                .parse()
+                .with_context(|| format!("'{size}' is not a valid window size"))?;
+            eyre::ensure!(size > 0, "window size must be positive");
+            Strategy::SlidingWindow(size)
+        }
+        ("sliding-window", None) => {
+            eyre::bail!("'sliding-window' requires a window size (e.g., 'sliding-window:10')")
+        }
+        ("token-budget", None) => Strategy::TokenBudget,
+        ("summarize", None) => Strategy::Summarize,
+        ("none", None) => Strategy::None,
+        ("similarity" | "token-budget" | "summarize" | "none", Some(_)) => {
+            eyre::bail!("'{name}' does not take an argument")
+        }
+        _ => eyre::bail!(
+            "unknown context strategy '{name}' (expected 'similarity', 'sliding-window:N', \
+             'token-budget', 'summarize' or 'none')"
+        ),
+    };
+    Ok(strategy)
+}
+
+/// Why [`Similarity`] cuts a chat where it does.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SimilarityAnalysis {
+    /// Similarity of each message before the last exchange to it,
+    /// or `None` for messages whose embeddings can't be compared.
+    pub similarities: Vec<Option<f32>>,
+    /// The message most similar to the last exchange,
+    /// or the question it answers.
+    pub most_similar: usize,
+    /// The message least similar to the last exchange,
+    /// or the question it answers.
+    pub least_similar: usize,
+    /// Similarity of the message most similar to the last exchange,
+    /// which may be the reply after [`most_similar`](Self::most_similar).
+    pub highest_similarity: f32,
+    /// Similarity of the message least similar to the last exchange,
+    /// which may be the reply after [`least_similar`](Self::least_similar).
+    pub lowest_similarity: f32,
+}
+
+impl SimilarityAnalysis {
+    /// The first message kept if the chat is cut at all,
+    /// which happens only if the least similar message comes before the most
+    /// similar one.
+    #[inline]
+    pub fn cut(&self) -> Option<usize> {
+        (self.most_similar > self.least_similar).then_some(self.least_similar)
+    }
+
+    /// The message a `similarity` reported for the message at `n` belongs to,
+    /// which is the reply after it if only the reply scored that.
+    #[inline]
+    pub fn scored_message(&self, n: usize, similarity: f32) -> usize {
+        if self.similarities.get(n).copied().flatten() == Some(similarity) {
+            n
+        } else {
+            n + 1
+        }
+    }
+}
+
+impl Similarity {
+    /// Compare the messages of a chat to its last exchange.
+    ///
+    /// Chats with fewer than four messages,
+    /// or without earlier messages comparable to the last exchange,
+    /// are not analyzed.
+    #[inline]
+    pub fn analyze(chat: &[ChatEntry]) -> eyre::Result<Option<SimilarityAnalysis>> {
+        if chat.len() < 4 {
+            return Ok(None);
+        }
+
+        let embeddings = chat
+            .iter()
+            .map(|entry| entry.embedding.unpack())
+            .collect::<eyre::Result<Vec<_>>>()
+            .context("failed to unpack embeddings")?;
+        let (earlier, last_exchange) = embeddings.split_at(embeddings.len() - 2);
+        // Messages embedded by a different model (or not at all) can't be
+        // compared, so they are left out rather than ruling out truncation.
+        let Some(dimension) = last_exchange
+            .iter()
+            .rev()
+            .map(Vec::len)
+            .find(|&dimension| dimension > 0)
+        else {
+            return Ok(None);
+        };
+        let comparable = |embedding: &&Vec<f32>| embedding.len() == dimension;
+        let similarities: Vec<_> = earlier
+            .iter()
+            .map(|embedding| {
+                comparable(&embedding).then(|| {
+                    last_exchange
+                        .iter()
+                        .filter(comparable)
+                        .map(|last| cosine_similarity(embedding, last))
+                        .fold(f32::NEG_INFINITY, f32::max)
+                })
+            })
+            .collect();
+        let compared = || {
+            similarities
+                .iter()
+                .enumerate()
+                .filter_map(|(n, similarity)| similarity.as_ref().map(|similarity| (n, similarity)))
+        };
+
+        // Ties go to the earliest most similar and the latest least similar
+        // messages, so that nothing is truncated if all are alike.
+        let Some((mut most_similar, &highest_similarity)) =
+            compared().rev().max_by(|(_, x), (_, y)| x.total_cmp(y))
+        else {
+            return Ok(None);
+        };
+        let Some((mut least_similar, &lowest_similarity)) =
+            compared().rev().min_by(|(_, x), (_, y)| x.total_cmp(y))
+        else {
+            return Ok(None);
+        };
+
+        if chat[most_similar].message.role == Role::Assistant {
+            most_similar = most_similar.saturating_sub(1);
+        }
+        if chat[least_similar].message.role == Role::Assistant {
+            least_similar = least_similar.saturating_sub(1);
+        }
+
+        Ok(Some(SimilarityAnalysis {
+            similarities,
+            most_similar,
+            least_similar,
+            highest_similarity,
+            lowest_similarity,
+        }))
+    }
+}
+
+impl ContextStrategy for Similarity {
+    #[inline]
+    fn split(&self, mut chat: Vec<ChatEntry>) -> eyre::Result<Split> {
+        let Some(cut) = Self::analyze(&chat)?.and_then(|analysis| analysis.cut()) else {
+            return Ok((chat, None));
+        };
+
+        let current_chat = chat.split_off(cut);
+        let outdated_chat = chat;
+
+        Ok((current_chat, Some(outdated_chat)))
+    }
+}
+
+impl ContextStrategy for SlidingWindow {
+    #[inline]
+    fn split(&self, mut chat: Vec<ChatEntry>) -> eyre::Result<Split> {
+        if chat.len() <= self.0 {
+            return Ok((chat, None));
+        }
+
+        // Replies are never kept without the question they answer,
+        // and the last exchange is always kept whole.
+        let last_exchange = chat
+            .iter()
+            .rposition(|entry| entry.message.role != Role::Assistant)
+            .unwrap_or(0);
+        let mut cut = chat.len() - self.0;
+        while cut < last_exchange && chat[cut].message.role == Role::Assistant {
+            cut += 1;
+        }
+        let cut = cut.min(last_exchange);
+        if cut == 0 {
+            return Ok((chat, None));
+        }
+
+        let current_chat = chat.split_off(cut);
+        let outdated_chat = chat;
+
+        Ok((current_chat, Some(outdated_chat)))
+    }
+}
+
+impl ContextStrategy for &TokenBudget {
+    #[inline]
+    fn split(&self, chat: Vec<ChatEntry>) -> eyre::Result<Split> {
+        tokens::fit_chat_to_budget(chat, self)
+    }
+}
+
+impl ContextStrategy for NoTruncation {
+    #[inline]
+    fn split(&self, chat: Vec<ChatEntry>) -> eyre::Result<Split> {
+        Ok((chat, None))
+    }
+}
+
+impl<S: ContextStrategy> ContextStrategy for KeepPinned<S> {
+    #[inline]
+    fn split(&self, chat: Vec<ChatEntry>) -> eyre::Result<Split> {
+        let (current_chat, outdated_chat) = self.0.split(chat)?;
+        let Some(outdated_chat) = outdated_chat else {
+            return Ok((current_chat, None));
+        };
+
+        // Outdated messages come before the ones kept,
+        // so the order of the chat is preserved.
+        let (pinned, outdated_chat): (Vec<_>, Vec<_>) = outdated_chat
+            .into_iter()
+            .partition(|entry| entry.metadata.pinned);
+        let current_chat = pinned.into_iter().chain(current_chat).collect();
+
+        Ok((current_chat, Some(outdated_chat)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::collection;
+    use proptest::prelude::any;
+    use proptest::prop_assert;
+    use proptest::prop_assert_eq;
+    use proptest::proptest;
+    use proptest::strategy::Strategy as _;
+
+    use super::*;
+    use crate::history::test_chat;
+    use crate::history::test_entry;
+
+    fn chat(embeddings: &[[f32; 2]]) -> Vec<ChatEntry> {
+        test_chat(
+            embeddings
+                .iter()
+                .enumerate()
+                .map(|(n, embedding)| (n.to_string(), embedding.to_vec())),
+        )
+    }
+
+    /// Chats with arbitrary roles and embeddings of a common dimension,
+    /// including zero vectors and non-finite components.
+    fn arbitrary_chat() -> impl proptest::strategy::Strategy<Value = Vec<ChatEntry>> {
+        (1..4_usize)
+            .prop_flat_map(|dimension| {
+                collection::vec(
+                    (
+                        any::<bool>(),
+                        collection::vec(proptest::num::f32::ANY, dimension),
+                    ),
+                    0..12,
+                )
+            })
+            .prop_map(|entries| {
+                entries
+                    .into_iter()
+                    .enumerate()
+                    .map(|(n, (is_user, embedding))| {
+                        let role = if is_user { Role::User } else { Role::Assistant };
+                        test_entry(role, &n.to_string(), embedding)
+                    })
+                    .collect()
+            })
+    }
+
+    fn contents(chat: &[ChatEntry]) -> Vec<&str> {
+        chat.iter()
+            .map(|entry| entry.message.content.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn strategy_parser_works() {
+        assert_eq!(strategy_parser("similarity").unwrap(), Strategy::Similarity);
+        assert_eq!(
+            strategy_parser("sliding-window:10").unwrap(),
+            Strategy::SlidingWindow(10)
+        );
+        assert_eq!(
+            strategy_parser("token-budget").unwrap(),
+            Strategy::TokenBudget
+        );
+        assert_eq!(strategy_parser("summarize").unwrap(), Strategy::Summarize);
+        assert_eq!(strategy_parser("none").unwrap(), Strategy::None);
+        assert!(strategy_parser("sliding-window").is_err());
+        assert!(strategy_parser("sliding-window:0").is_err());
+        assert!(strategy_parser("none:1").is_err());
+        assert!(strategy_parser("magic").is_err());
+
+        for strategy in [
+            Strategy::Similarity,
+            Strategy::SlidingWindow(3),
+            Strategy::Summarize,
+            Strategy::None,
+        ] {
+            assert_eq!(strategy_parser(&strategy.to_string()).unwrap(), strategy);
+        }
+    }
+
+    #[test]
+    fn similarity_drops_unrelated_beginning() -> eyre::Result<()> {
+        let chat = chat(&[
+            [1.0, 0.5],
+            [1.0, 0.5],
+            [0.0, 1.0],
+            [0.0, 1.0],
+            [1.0, 0.0],
+            [1.0, 0.0],
+            [1.0, 0.0],
+            [1.0, 0.0],
+        ]);
+        let analysis = Similarity::analyze(&chat)?.unwrap();
+        assert_eq!(analysis.similarities.len(), 6);
+        assert_eq!(
+            (
+                analysis.most_similar,
+                analysis.least_similar,
+                analysis.cut()
+            ),
+            (4, 2, Some(2))
+        );
+        let (current_chat, outdated_chat) = Similarity.split(chat)?;
+        assert_eq!(contents(&current_chat), ["2", "3", "4", "5", "6", "7"]);
+        assert_eq!(contents(&outdated_chat.unwrap()), ["0", "1"]);
+
+        let analysis = Similarity::analyze(&self::chat(&[
+            [0.0, 1.0],
+            [0.0, 1.0],
+            [0.6, 0.8],
+            [1.0, 0.0],
+            [1.0, 0.0],
+            [1.0, 0.0],
+        ]))?
+        .unwrap();
+        assert_eq!((analysis.most_similar, analysis.least_similar), (2, 0));
+        assert_eq!(
+            (analysis.highest_similarity, analysis.lowest_similarity),
+            (1.0, 0.0)
+        );
+        assert_eq!(
+            analysis.scored_message(analysis.most_similar, analysis.highest_similarity),
+            3
+        );
+        assert_eq!(
+            analysis.scored_message(analysis.least_similar, analysis.lowest_similarity),
+            0
+        );
+
+        let (current_chat, outdated_chat) = Similarity.split(self::chat(&[[1.0, 0.0]; 3]))?;
+        assert_eq!(current_chat.len(), 3);
+        assert!(outdated_chat.is_none());
+
+        let mut chat = self::chat(&[[1.0, 0.0]; 6]);
+        chat[0].embedding = Vec::new().into();
+        let (current_chat, outdated_chat) = Similarity.split(chat)?;
+        assert_eq!(current_chat.len(), 6);
+        assert!(outdated_chat.is_none());
+
+        let mut chat = self::chat(&[
+            [1.0, 0.5],
+            [1.0, 0.5],
+            [0.0, 1.0],
+            [0.0, 1.0],
+            [1.0, 0.0],
+            [1.0, 0.0],
+            [1.0, 0.0],
+            [1.0, 0.0],
+        ]);
+        chat[1].embedding = Vec::new().into();
+        chat[3].embedding = vec![0.0, 1.0, 0.0].into();
+        chat[7].embedding = Vec::new().into();
+        let analysis = Similarity::analyze(&chat)?.unwrap();
+        assert_eq!(
+            analysis
+                .similarities
+                .iter()
+                .map(Option::is_some)
+                .collect::<Vec<_>>(),
+            [true, false, true, false, true, true]
+        );
+        assert_eq!(analysis.cut(), Some(2));
+        let (current_chat, outdated_chat) = Similarity.split(chat)?;
+        assert_eq!(contents(&current_chat), ["2", "3", "4", "5", "6", "7"]);
+        assert_eq!(contents(&outdated_chat.unwrap()), ["0", "1"]);
+        Ok(())
+    }
+
+    #[test]
+    fn sliding_window_keeps_most_recent() -> eyre::Result<()> {
+        let (current_chat, outdated_chat) = SlidingWindow(3).split(chat(&[[1.0, 0.0]; 6]))?;
+        assert_eq!(contents(&current_chat), ["4", "5"]);
+        assert_eq!(current_chat[0].message.role, Role::User);
+        assert_eq!(contents(&outdated_chat.unwrap()), ["0", "1", "2", "3"]);
+
+        let (current_chat, outdated_chat) = SlidingWindow(4).split(chat(&[[1.0, 0.0]; 6]))?;
+        assert_eq!(contents(&current_chat), ["2", "3", "4", "5"]);
+        assert_eq!(contents(&outdated_chat.unwrap()), ["0", "1"]);
+
+        let (current_chat, outdated_chat) = SlidingWindow(1).split(chat(&[[1.0, 0.0]; 6]))?;
+        assert_eq!(contents(&current_chat), ["4", "5"]);
+        assert_eq!(contents(&outdated_chat.unwrap()), ["0", "1", "2", "3"]);
+
+        let (current_chat, outdated_chat) = SlidingWindow(8).split(chat(&[[1.0, 0.0]; 5]))?;
+        assert_eq!(current_chat.len(), 5);
+        assert!(outdated_chat.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn keep_pinned_keeps_pinned_messages() -> eyre::Result<()> {
+        let mut chat = chat(&[[1.0, 0.0]; 6]);
+        chat[1].metadata.pinned = true;
+        let (current_chat, outdated_chat) = KeepPinned(SlidingWindow(2)).split(chat)?;
+        assert_eq!(contents(&current_chat), ["1", "4", "5"]);
+        assert_eq!(contents(&outdated_chat.unwrap()), ["0", "2", "3"]);
+        Ok(())
+    }
+
+    #[test]
+    fn no_truncation_keeps_everything() -> eyre::Result<()> {
+        let (current_chat, outdated_chat) = NoTruncation.split(chat(&[[1.0, 0.0]; 7]))?;
+        assert_eq!(current_chat.len(), 7);
+        assert!(outdated_chat.is_none());
+        Ok(())
+    }
+
+    proptest! {
+        #[test]
+        fn similarity_keeps_every_message(chat in arbitrary_chat()) {
+            let original: Vec<_> = contents(&chat).into_iter().map(String::from).collect();
+            let (current_chat, outdated_chat) = Similarity.split(chat).unwrap();
+
+            if original.len() < 4 {
+                prop_assert!(outdated_chat.is_none());
+            }
+            prop_assert!(current_chat.len() >= original.len().min(3));
+            let outdated_chat = outdated_chat.unwrap_or_default();
+            let mut split = contents(&outdated_chat);
+            split.extend(contents(&current_chat));
+            prop_assert_eq!(split, original);
+        }
+
+        #[test]
+        fn similarity_keeps_alike_chats(
+            chat in arbitrary_chat()
+                .prop_map(|mut chat| {
+                    if let Some(first) = chat.first().map(|entry| entry.embedding.clone()) {
+                        chat.iter_mut().for_each(|entry| entry.embedding = first.clone());
+                    }
+                    chat
+                })
+        ) {
+            let len = chat.len();
+            let (current_chat, outdated_chat) = Similarity.split(chat).unwrap();
+            prop_assert_eq!(current_chat.len(), len);
+            prop_assert!(outdated_chat.is_none());
+        }
+    }
+}
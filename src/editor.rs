@@ -0,0 +1,129 @@
+//! Editing messages and other files in the editor of the user.
+
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::process;
+
+use color_eyre::eyre;
+use color_eyre::eyre::Context;
+
+/// Editor used when neither `$VISUAL` nor `$EDITOR` are set.
+const FALLBACK_EDITOR: &str = "vi";
+
+/// What the editor starts with.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Draft {
+    /// An empty file.
+    Empty,
+
+    /// The previous message of the user.
+    LastMessage,
+
+    /// The contents of a template file.
+    Template(String),
+}
+
+/// Either nothing, `last` or `@path`.
+#[inline]
+pub fn draft_parser(draft: &str) -> eyre::Result<Draft> {
+    Ok(match draft {
+        "" => Draft::Empty,
+        "last" => Draft::LastMessage,
+        _ => match draft.strip_prefix('@') {
+            Some(path) => Draft::Template(
+                fs::read_to_string(path)
+                    .with_context(|| format!("failed to read template from {path}"))?,
+            ),
+            None => eyre::bail!("'{draft}' is neither 'last' nor '@path' to a template"),
+        },
+    })
+}
+
+/// The editor selected by the user,
+/// as a program followed by its arguments (e.g., `code --wait`).
+#[inline]
+fn editor_command() -> String {
+    ["VISUAL", "EDITOR"]
+        .into_iter()
+        .filter_map(|var| env::var(var).ok())
+        .find(|editor| !editor.trim().is_empty())
+        .unwrap_or_else(|| FALLBACK_EDITOR.into())
+}
+
+/// Let the user edit `initial` in their editor,
+/// returning what they saved.
+#[inline]
+pub fn edit(initial: &str) -> eyre::Result<String> {
+    edit_with(&editor_command(), initial)
+}
+
+#[inline]
+fn edit_with(editor: &str, initial: &str) -> eyre::Result<String> {
+    let mut file = tempfile::Builder::new()
+        .prefix("cligpt-")
+        .suffix(".md")
+        .tempfile()
+        .context("failed to create a temporary file")?;
+    file.write_all(initial.as_bytes())
+        .and_then(|()| file.flush())
+        .context("failed to write the temporary file")?;
+
+    run_editor(editor, file.path())?;
+    // Editors may replace the file instead of writing to it.
+    fs::read_to_string(file.path()).context("failed to read the edited message")
+}
+
+/// Let the user edit the file at `path` in their editor.
+#[inline]
+pub fn edit_file(path: &Path) -> eyre::Result<()> {
+    run_editor(&editor_command(), path)
+}
+
+#[inline]
+fn run_editor(editor: &str, path: &Path) -> eyre::Result<()> {
+    let mut words = editor.split_whitespace();
+    let Some(program) = words.next() else {
+        eyre::bail!("cannot use empty string as editor");
+    };
+    let status = process::Command::new(program)
+        .args(words)
+        .arg(path)
+        .status()
+        .with_context(|| format!("failed to run editor '{editor}'"))?;
+    eyre::ensure!(status.success(), "editor '{editor}' failed ({status})");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn draft_parser_works() -> eyre::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("template.md");
+        fs::write(&path, "## Question\n")?;
+
+        assert_eq!(draft_parser("")?, Draft::Empty);
+        assert_eq!(draft_parser("last")?, Draft::LastMessage);
+        assert_eq!(
+            draft_parser(&format!("@{}", path.display()))?,
+            Draft::Template("## Question\n".into())
+        );
+        assert!(draft_parser("@/nonexistent/template.md").is_err());
+        assert!(draft_parser("first").is_err());
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn edit_with_works() -> eyre::Result<()> {
+        assert_eq!(edit_with("true", "unchanged")?, "unchanged");
+        assert_eq!(edit_with("sed -i s/old/new/", "old text")?, "new text");
+        assert!(edit_with("false", "").is_err());
+        assert!(edit_with(" ", "").is_err());
+        Ok(())
+    }
+}
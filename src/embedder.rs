@@ -0,0 +1,217 @@
+//! Backends computing embeddings of messages.
+
+use async_openai::types::CreateEmbeddingRequestArgs;
+use async_openai::Client;
+use color_eyre::eyre;
+use color_eyre::eyre::Context;
+
+use crate::cache::EmbeddingCache;
+use crate::Embedding;
+
+/// Embedding model used unless told otherwise.
+pub const DEFAULT_EMBEDDING_MODEL: &str = "text-embedding-ada-002";
+/// Dimension of the embeddings of [`DEFAULT_EMBEDDING_MODEL`].
+pub const DEFAULT_EMBEDDING_DIMENSION: usize = 1536;
+/// Dimension of offline embeddings unless told otherwise.
+pub const DEFAULT_OFFLINE_EMBEDDING_DIMENSION: usize = 512;
+
+/// Ways of embedding messages selectable from the command line.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum EmbeddingModel {
+    /// An embedding model offered by the API.
+    Api(String),
+
+    /// Hashed bag-of-words computed locally.
+    Offline,
+
+    /// Don't embed messages at all.
+    None,
+}
+
+#[inline]
+pub fn embedding_model_parser(model: &str) -> eyre::Result<EmbeddingModel> {
+    let model = model.trim();
+    eyre::ensure!(
+        !model.is_empty(),
+        "cannot use empty string as embedding model name"
+    );
+
+    Ok(match model {
+        "offline" => EmbeddingModel::Offline,
+        "none" => EmbeddingModel::None,
+        _ => EmbeddingModel::Api(model.into()),
+    })
+}
+
+#[inline]
+pub fn embedding_dimension_parser(dimension: &str) -> eyre::Result<usize> {
+    let dimension: usize = dimension
+        .parse()
+        .with_context(|| format!("'{dimension}' is not a valid embedding dimension"))?;
+    eyre::ensure!(dimension > 0, "embedding dimension must be positive");
+    Ok(dimension)
+}
+
+/// Something that computes embeddings.
+pub enum Embedder {
+    /// Ask the API,
+    /// unless the embedding is cached already.
+    Api {
+        client: Box<Client>,
+        cache: EmbeddingCache,
+        model: String,
+        dimension: Option<usize>,
+    },
+
+    /// See [`hashed_bag_of_words`].
+    Offline { dimension: usize },
+
+    /// Embeddings are always empty.
+    None,
+}
+
+impl Embedder {
+    /// Whether the embeddings computed are meaningful at all.
+    #[inline]
+    pub fn is_none(&self) -> bool {
+        matches!(self, Self::None)
+    }
+
+    #[inline]
+    pub async fn embed(&self, input: &str) -> eyre::Result<Embedding> {
+        match self {
+            Self::Api {
+                client,
+                cache,
+                model,
+                dimension,
+            } => embed_with_api(client, cache, model, *dimension, input).await,
+            Self::Offline { dimension } => Ok(hashed_bag_of_words(input, *dimension)),
+            Self::None => Ok(Embedding::new()),
+        }
+    }
+}
+
+/// Embed `input` using the API,
+/// asking it only if the embedding isn't cached yet.
+///
+/// Problems with the cache only cause warnings,
+/// since the API can always be asked instead.
+#[inline]
+async fn embed_with_api(
+    client: &Client,
+    cache: &EmbeddingCache,
+    model: &str,
+    dimension: Option<usize>,
+    input: &str,
+) -> eyre::Result<Embedding> {
+    let has_valid_dimension =
+        |embedding: &Embedding| dimension.map_or(!embedding.is_empty(), |n| embedding.len() == n);
+
+    match cache.get(client.api_base(), model, input) {
+        Ok(Some(embedding)) if has_valid_dimension(&embedding) => return Ok(embedding),
+        Ok(_) => {}
+        Err(error) => eprintln!("warning: ignoring cached embedding: {error:#}"),
+    }
+
+    let request = CreateEmbeddingRequestArgs::default()
+        .model(model)
+        .input(input)
+        .build()
+        .context("failed to create embedding request")?;
+    let response = client
+        .embeddings()
+        .create(request)
+        .await
+        .context("failed to obtain embedding response")?;
+    let data = response.data.into_iter().next();
+    let embedding = data
+        .map(|data| data.embedding)
+        .ok_or_else(|| eyre::eyre!("failed to embed '{input}'"))?;
+    eyre::ensure!(
+        has_valid_dimension(&embedding),
+        "embedding has incorrect dimension (expected {}, got {})",
+        dimension.map_or_else(|| "a non-empty embedding".into(), |n| n.to_string()),
+        embedding.len()
+    );
+    if let Err(error) = cache.insert(client.api_base(), model, input, &embedding) {
+        eprintln!("warning: failed to cache embedding: {error:#}");
+    }
+    Ok(embedding)
+}
+
+/// A cheap embedding of `input` that needs no model at all.
+///
+/// Each (case-insensitive) word is hashed to a signed position of the
+/// embedding,
+/// so texts sharing many words have similar embeddings.
+/// The result has unit length,
+/// unless `input` has no words.
+#[inline]
+pub fn hashed_bag_of_words(input: &str, dimension: usize) -> Embedding {
+    // https://en.wikipedia.org/wiki/Fowler%E2%80%93Noll%E2%80%93Vo_hash_function
+    #[inline]
+    fn fnv1a(word: &str) -> u64 {
+        word.bytes().fold(0xCBF2_9CE4_8422_2325, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01B3)
+        })
+    }
+
+    let mut embedding = vec![0.0; dimension];
+    if dimension == 0 {
+        return embedding;
+    }
+    for word in input
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+    {
+        let hash = fnv1a(&word.to_lowercase());
+        let sign = if hash >> 63 == 0 { 1.0 } else { -1.0 };
+        embedding[(hash % dimension as u64) as usize] += sign;
+    }
+
+    let norm = embedding.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        embedding.iter_mut().for_each(|x| *x /= norm);
+    }
+    embedding
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_relative_eq;
+
+    use super::*;
+    use crate::cosine_similarity;
+
+    #[test]
+    fn embedding_model_parser_works() {
+        assert_eq!(
+            embedding_model_parser("offline").unwrap(),
+            EmbeddingModel::Offline
+        );
+        assert_eq!(
+            embedding_model_parser("none").unwrap(),
+            EmbeddingModel::None
+        );
+        assert_eq!(
+            embedding_model_parser(" text-embedding-3-small ").unwrap(),
+            EmbeddingModel::Api("text-embedding-3-small".into())
+        );
+        assert!(embedding_model_parser("").is_err());
+        assert!(embedding_dimension_parser("0").is_err());
+        assert_eq!(embedding_dimension_parser("256").unwrap(), 256);
+    }
+
+    #[test]
+    fn hashed_bag_of_words_works() {
+        let cat = hashed_bag_of_words("My cat is named Tom.", 512);
+        let question = hashed_bag_of_words("what is my CAT named?", 512);
+        let unrelated = hashed_bag_of_words("Rust borrow checker errors", 512);
+
+        assert_eq!(cat.len(), 512);
+        assert_relative_eq!(cosine_similarity(&cat, &cat), 1.0, epsilon = 1e-6);
+        assert!(cosine_similarity(&cat, &question) > cosine_similarity(&cat, &unrelated));
+        assert!(hashed_bag_of_words("...", 512).iter().all(|&x| x == 0.0));
+    }
+}
@@ -0,0 +1,99 @@
+//! Compact storage of message embeddings.
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine as _;
+use color_eyre::eyre;
+use color_eyre::eyre::Context;
+use serde::Deserialize;
+use serde::Deserializer;
+use serde::Serialize;
+
+use crate::Embedding;
+
+/// An embedding stored as base64-encoded little-endian `f32`s.
+///
+/// This is several times smaller than a JSON array of numbers and is cheap
+/// to deserialize,
+/// since the actual floats are only decoded when [`unpack`](Self::unpack) is
+/// called.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+#[serde(transparent)]
+pub struct PackedEmbedding(String);
+
+impl PackedEmbedding {
+    #[inline]
+    pub fn pack(embedding: &[f32]) -> Self {
+        let bytes: Vec<u8> = embedding
+            .iter()
+            .flat_map(|value| value.to_le_bytes())
+            .collect();
+        Self(BASE64.encode(bytes))
+    }
+
+    #[inline]
+    pub fn unpack(&self) -> eyre::Result<Embedding> {
+        let bytes = BASE64
+            .decode(&self.0)
+            .context("failed to decode packed embedding")?;
+        eyre::ensure!(
+            bytes.len() % 4 == 0,
+            "packed embedding has invalid length ({} bytes)",
+            bytes.len()
+        );
+        Ok(bytes
+            .chunks_exact(4)
+            .map(|chunk| f32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
+            .collect())
+    }
+}
+
+impl From<Embedding> for PackedEmbedding {
+    #[inline]
+    fn from(embedding: Embedding) -> Self {
+        Self::pack(&embedding)
+    }
+}
+
+// Embeddings used to be stored as plain arrays of numbers,
+// which are still accepted (and packed) when read.
+impl<'de> Deserialize<'de> for PackedEmbedding {
+    #[inline]
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Packed(String),
+            Unpacked(Embedding),
+        }
+
+        Ok(match Repr::deserialize(deserializer)? {
+            Repr::Packed(packed) => Self(packed),
+            Repr::Unpacked(embedding) => Self::pack(&embedding),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packed_embedding_roundtrip_works() -> eyre::Result<()> {
+        let embedding = vec![0.0, -1.5, 0.333_333_34, f32::MAX];
+        let packed = PackedEmbedding::pack(&embedding);
+        assert_eq!(packed.unpack()?, embedding);
+
+        let json = serde_json::to_string(&packed)?;
+        assert_eq!(serde_json::from_str::<PackedEmbedding>(&json)?, packed);
+        assert_eq!(
+            serde_json::from_str::<PackedEmbedding>("[0.0, -1.5, 0.33333334, 3.4028235e38]")?,
+            packed
+        );
+
+        assert!(PackedEmbedding("AAA=".into()).unpack().is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,381 @@
+//! Crash-safe, versioned persistence of the chat history.
+//!
+//! Chats are stored in an envelope carrying a schema version,
+//! session metadata and per-message metadata.
+//! Older formats are migrated automatically when read.
+//!
+//! Files are never written in place:
+//! contents go to a temporary file in the same directory,
+//! which is synced to disk and then renamed over the original.
+//! The previous version is kept next to it as a `.bak` file,
+//! which is used as a fallback whenever the primary file can't be read.
+
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use async_openai::types::ChatCompletionRequestMessage;
+#[cfg(test)]
+use async_openai::types::ChatCompletionRequestMessageArgs;
+#[cfg(test)]
+use async_openai::types::Role;
+use color_eyre::eyre;
+use color_eyre::eyre::Context;
+use serde::Deserialize;
+use serde::Serialize;
+use tempfile::NamedTempFile;
+
+use crate::embedding::PackedEmbedding;
+use crate::Embedding;
+
+/// Current version of the on-disk chat format.
+///
+/// Version 2 replaced the arrays of numbers used for embeddings in version 1
+/// with packed embeddings.
+pub const CHAT_VERSION: u64 = 2;
+
+/// A chat session as stored on disk.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Chat {
+    pub version: u64,
+    #[serde(default)]
+    pub metadata: ChatMetadata,
+    pub messages: Vec<ChatEntry>,
+}
+
+/// Information about a chat session as a whole.
+///
+/// Timestamps are in seconds since the Unix epoch.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct ChatMetadata {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub updated: Option<u64>,
+    /// System prompt sent at the head of every request.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub system: Option<String>,
+    /// Running summary of the messages truncated from the chat.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub summary: Option<String>,
+}
+
+/// A single chat message together with its embedding.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ChatEntry {
+    pub message: ChatCompletionRequestMessage,
+    pub embedding: PackedEmbedding,
+    #[serde(default)]
+    pub metadata: MessageMetadata,
+}
+
+/// Information about a single message and how it came to be.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct MessageMetadata {
+    /// When the message was sent or received,
+    /// in seconds since the Unix epoch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<u64>,
+    /// Model that generated the message.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    /// Temperature used to generate the message.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    /// Number of tokens in the message.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tokens: Option<usize>,
+    /// Whether the message always stays in the chat context.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub pinned: bool,
+}
+
+/// The original format: a bare array of `(message, embedding)` pairs.
+type LegacyChat = Vec<(ChatCompletionRequestMessage, Embedding)>;
+
+impl Default for Chat {
+    #[inline]
+    fn default() -> Self {
+        Self {
+            version: CHAT_VERSION,
+            metadata: ChatMetadata::default(),
+            messages: Vec::new(),
+        }
+    }
+}
+
+impl ChatEntry {
+    #[inline]
+    pub fn new(message: ChatCompletionRequestMessage, embedding: PackedEmbedding) -> Self {
+        Self {
+            message,
+            embedding,
+            metadata: MessageMetadata::default(),
+        }
+    }
+}
+
+/// A chat entry for tests.
+#[cfg(test)]
+#[inline]
+pub(crate) fn test_entry(role: Role, content: &str, embedding: Embedding) -> ChatEntry {
+    let message = ChatCompletionRequestMessageArgs::default()
+        .role(role)
+        .content(content)
+        .build()
+        .expect("test messages are valid");
+    ChatEntry::new(message, embedding.into())
+}
+
+/// Chat entries for tests,
+/// alternating between user and assistant messages.
+#[cfg(test)]
+#[inline]
+pub(crate) fn test_chat<C: AsRef<str>>(
+    messages: impl IntoIterator<Item = (C, Embedding)>,
+) -> Vec<ChatEntry> {
+    messages
+        .into_iter()
+        .enumerate()
+        .map(|(n, (content, embedding))| {
+            let role = if n % 2 == 0 {
+                Role::User
+            } else {
+                Role::Assistant
+            };
+            test_entry(role, content.as_ref(), embedding)
+        })
+        .collect()
+}
+
+/// Current time in seconds since the Unix epoch.
+#[inline]
+pub fn now() -> eyre::Result<u64> {
+    Ok(SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .context("system clock is set before the Unix epoch")?
+        .as_secs())
+}
+
+#[inline]
+pub fn read_chat_from_path(path: impl AsRef<Path>) -> eyre::Result<Chat> {
+    let path = path.as_ref();
+
+    match read_chat_from_file(path) {
+        Ok(chat) => Ok(chat),
+        Err(err) => {
+            let backup_path = backup_path(path);
+            if !backup_path
+                .try_exists()
+                .context("failed to check if chat history backup exists")?
+            {
+                return Err(err);
+            }
+
+            let chat = read_chat_from_file(&backup_path).map_err(|_| err)?;
+            eprintln!(
+                "warning: {} is unreadable, using backup from {}",
+                path.display(),
+                backup_path.display()
+            );
+            Ok(chat)
+        }
+    }
+}
+
+#[inline]
+pub fn write_chat_to_path(chat: &Chat, path: impl AsRef<Path>) -> eyre::Result<()> {
+    let path = path.as_ref();
+
+    if path
+        .try_exists()
+        .context("failed to check if chat history file exists")?
+        && read_chat_from_file(path).is_ok()
+    {
+        let backup_path = backup_path(path);
+        write_atomically(&backup_path, |writer| {
+            let mut file = fs::File::open(path)
+                .with_context(|| format!("failed to open {}", path.display()))?;
+            io::copy(&mut file, writer)
+                .with_context(|| format!("failed to copy {}", path.display()))?;
+            Ok(())
+        })
+        .context("failed to back up chat history")?;
+    }
+
+    write_atomically(path, |writer| {
+        serde_json::to_writer(writer, chat)
+            .with_context(|| format!("failed to serialize contents to {}", path.display()))
+    })
+}
+
+/// Replace the contents of a file without ever leaving it partially written.
+#[inline]
+pub fn write_atomically(
+    path: impl AsRef<Path>,
+    write: impl FnOnce(&mut io::BufWriter<&fs::File>) -> eyre::Result<()>,
+) -> eyre::Result<()> {
+    let path = path.as_ref();
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+
+    let file = NamedTempFile::new_in(dir)
+        .with_context(|| format!("failed to create temporary file in {}", dir.display()))?;
+    {
+        let mut writer = io::BufWriter::new(file.as_file());
+        write(&mut writer)?;
+        writer
+            .flush()
+            .with_context(|| format!("failed to flush contents of {}", path.display()))?;
+    }
+    file.as_file()
+        .sync_all()
+        .with_context(|| format!("failed to sync contents of {}", path.display()))?;
+    file.persist(path)
+        .with_context(|| format!("failed to replace {}", path.display()))?;
+    sync_dir(dir)?;
+
+    Ok(())
+}
+
+#[inline]
+fn read_chat_from_file(path: &Path) -> eyre::Result<Chat> {
+    let chat = if path
+        .try_exists()
+        .context("failed to check if chat history file exists")?
+    {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("failed to read from {}", path.display()))?;
+
+        // https://github.com/serde-rs/json/issues/160#issuecomment-253446892
+        let value = serde_json::from_str(&contents)
+            .with_context(|| format!("failed to deserialize contents of {}", path.display()))?;
+        migrate_chat(value)
+            .with_context(|| format!("failed to migrate contents of {}", path.display()))?
+    } else {
+        Chat::default()
+    };
+    Ok(chat)
+}
+
+/// Bring a chat stored in any known format up to the current version.
+#[inline]
+fn migrate_chat(value: serde_json::Value) -> eyre::Result<Chat> {
+    if value.is_array() {
+        let chat: LegacyChat =
+            serde_json::from_value(value).context("failed to deserialize legacy chat")?;
+        return Ok(Chat {
+            messages: chat
+                .into_iter()
+                .map(|(message, embedding)| ChatEntry::new(message, embedding.into()))
+                .collect(),
+            ..Chat::default()
+        });
+    }
+
+    let version = value
+        .get("version")
+        .and_then(serde_json::Value::as_u64)
+        .ok_or_else(|| eyre::eyre!("chat has no version"))?;
+    match version {
+        // Packed embeddings also accept arrays of numbers,
+        // so version 1 is read as is and upgraded on the next write.
+        1 | CHAT_VERSION => {
+            let mut chat: Chat =
+                serde_json::from_value(value).context("failed to deserialize chat")?;
+            chat.version = CHAT_VERSION;
+            Ok(chat)
+        }
+        _ => eyre::bail!(
+            "chat version {version} is not supported (maybe it was written by a newer \
+             cligpt?)"
+        ),
+    }
+}
+
+#[inline]
+fn backup_path(path: &Path) -> PathBuf {
+    let mut backup_path = OsString::from(path.as_os_str());
+    backup_path.push(".bak");
+    backup_path.into()
+}
+
+// Renames are only durable once the directory entry itself is synced.
+#[cfg(unix)]
+#[inline]
+fn sync_dir(dir: &Path) -> eyre::Result<()> {
+    fs::File::open(dir)
+        .and_then(|dir| dir.sync_all())
+        .with_context(|| format!("failed to sync {}", dir.display()))
+}
+
+#[cfg(not(unix))]
+#[inline]
+fn sync_dir(_dir: &Path) -> eyre::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chat(content: &str) -> Chat {
+        Chat {
+            messages: vec![test_entry(Role::User, content, vec![1.0, 0.0])],
+            ..Chat::default()
+        }
+    }
+
+    #[test]
+    fn legacy_chat_is_migrated() -> eyre::Result<()> {
+        let value = serde_json::json!([
+            [{"role": "user", "content": "Hi"}, [1.0, 0.0]],
+            [{"role": "assistant", "content": "Hello!"}, [0.0, 1.0]],
+        ]);
+        let chat = migrate_chat(value)?;
+        assert_eq!(chat.version, CHAT_VERSION);
+        assert_eq!(chat.messages.len(), 2);
+        assert_eq!(chat.messages[1].message.content, "Hello!");
+        assert_eq!(chat.messages[1].embedding.unpack()?, [0.0, 1.0]);
+
+        assert!(migrate_chat(serde_json::json!({"version": 999, "messages": []})).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn corrupt_history_falls_back_to_backup() -> eyre::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("chat.json");
+        assert!(read_chat_from_path(&path)?.messages.is_empty());
+
+        write_chat_to_path(&chat("first"), &path)?;
+        write_chat_to_path(&chat("second"), &path)?;
+        assert_eq!(
+            read_chat_from_path(&path)?.messages[0].message.content,
+            "second"
+        );
+
+        fs::write(&path, "[[{\"role\": \"us")?;
+        assert_eq!(
+            read_chat_from_path(&path)?.messages[0].message.content,
+            "first"
+        );
+
+        // A corrupt primary must never overwrite a good backup.
+        write_chat_to_path(&chat("third"), &path)?;
+        assert_eq!(
+            read_chat_from_file(&backup_path(&path))?.messages[0]
+                .message
+                .content,
+            "first"
+        );
+        Ok(())
+    }
+}
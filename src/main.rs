@@ -25,6 +25,13 @@
 //!   [`gpt-3.5-turbo`](https://platform.openai.com/docs/guides/chat), default)
 //! - `--model=gpt4` ([GPT-4](https://openai.com/product/gpt-4), [`gpt-4`](https://platform.openai.com/docs/guides/chat))
 //!
+//! Any other model identifier is passed to the API as is,
+//! so dated snapshots (`--model=gpt-4-0314`),
+//! larger context windows (`--model=gpt-4-32k`),
+//! fine-tuned models (`--model=ft:...`)
+//! and the models of local servers can be used as well.
+//! Run `cligpt models` to list the models offered by the API.
+//!
 //! # Common use cases
 //!
 //! - Generate creative writing ideas or brainstorm topics.
@@ -39,7 +46,7 @@
 //!
 //! Before installing `cligpt`,
 //! you need to make sure you have
-//! [Rust](https://www.rust-lang.org/tools/install) (version 1.65.0 or later)
+//! [Rust](https://www.rust-lang.org/tools/install) (version 1.85.0 or later)
 //! and [Cargo](https://doc.rust-lang.org/cargo/),
 //! the package manager for Rust,
 //! installed.
@@ -122,14 +129,64 @@
 //! In the example above,
 //! the API key will be read from the environment.
 //!
-//! `cligpt` supports receiving input only from the standard input:
+//! `cligpt` can also talk to any `OpenAI`-compatible API,
+//! such as a local [llama.cpp](https://github.com/ggerganov/llama.cpp)
+//! server or a proxy gateway,
+//! by passing its base URL with `--api-base` or by setting the
+//! `OPENAI_API_BASE` environment variable.
+//! An API key is optional in that case and can be in any format:
+//!
+//! ```console
+//! $ echo 'Hello!' | cligpt --api-base http://localhost:8080/v1
+//! Hello! How can I help you today?
+//! ```
+//!
+//! When piped into,
+//! `cligpt` reads your message from the standard input:
 //!
 //! ```console
 //! $ echo "Repeat this message exactly how you read it" | cligpt
 //! Repeat this message exactly how you read it.
 //! ```
 //!
-//! `cligpt` also stores a single chat session,
+//! Quick questions can also be passed as arguments,
+//! and anything piped along is attached to them as a code block,
+//! which comes in handy in scripts:
+//!
+//! ```console
+//! $ cligpt 'What does this function do?' < src/lib.rs
+//! ```
+//!
+//! A message starting with the name of a command that takes no arguments
+//! (such as `cligpt show me how`) is sent
+//! as long as it doesn't make sense as that command.
+//! Other misused commands are reported as usage errors,
+//! but putting `--` before the message makes sure it is never taken for one:
+//!
+//! ```console
+//! $ cligpt -- search the bug in this code < src/lib.rs
+//! ```
+//!
+//! To ask about several files at once,
+//! attach them with `--file`,
+//! which also takes globs:
+//!
+//! ```console
+//! $ cligpt 'Where is the configuration parsed?' -f Cargo.toml -f 'src/**/*.rs'
+//! ```
+//!
+//! Each file is sent as a code block labelled with its path.
+//! Binary files, files that aren't valid UTF-8 and files over 100 KiB are skipped,
+//! and at most 400 KiB are attached to a single message.
+//!
+//! Longer messages are easier to write in your editor
+//! (`$VISUAL` or `$EDITOR`),
+//! which `--edit` opens for you.
+//! Start from your previous message with `--edit=last`,
+//! or from a template with `--edit=@template.md`.
+//! Leaving the message empty aborts without sending anything.
+//!
+//! `cligpt` also stores the current chat session,
 //! which can be viewed using
 //! `cligpt show`.
 //! For example:
@@ -166,13 +223,230 @@
 //! As of 2021, the population of Paris is estimated to be around 2.2 million people. However, the population of the greater Paris metropolitan area, which includes surrounding suburbs and municipalities, is estimated to be around 12 million people.
 //! ```
 //!
-//! Chat context is managed by truncating the chat in some situations where
+//! ## Interactive mode
+//!
+//! Run `cligpt` in a terminal without piping anything into it
+//! (or run `cligpt repl`)
+//! to chat interactively,
+//! with line editing and a history of your inputs:
+//!
+//! ```console
+//! $ cligpt
+//! Chatting in session 'default' with gpt-3.5-turbo. Type /help for help.
+//! >>> What is the capital of France?
+//!
+//! The capital of France is Paris.
+//! >>> /model gpt4
+//! >>> And its population?
+//! ...
+//! ```
+//!
+//! Each answer is stored in the chat session as soon as it is complete,
+//! just as if it had been piped into `cligpt`
+//! (with `--pin`, only the first message is pinned).
+//! End a line with `\` to continue your message on the next one.
+//! Commands start with `/`:
+//! `/reset` archives the chat and starts over,
+//! `/model` shows or switches the model,
+//! `/save path` writes the chat to a file
+//! and `/exit` (or Ctrl-D) leaves.
+//!
+//! ## Sessions
+//!
+//! You can keep several independent conversations around and switch
+//! between them:
+//!
+//! ```console
+//! $ cligpt sessions new debugging
+//! $ echo 'Why does my borrow checker complain?' | cligpt
+//! ...
+//!
+//! $ cligpt sessions switch default
+//! $ cligpt sessions list
+//!   debugging
+//! * default
+//! ```
+//!
+//! Sessions can also be renamed (`cligpt sessions rename old new`) and
+//! deleted (`cligpt sessions delete name`).
+//! To use a session just once without switching to it,
+//! pass `--session name` or set the `CLIGPT_SESSION` environment variable.
+//!
+//! Only one `cligpt` process can use a given session at a time
+//! (whether chatting in it, renaming it or deleting it).
+//! If another one is already using it,
+//! `cligpt` fails right away,
+//! unless you pass `--wait` to have it wait for its turn.
+//!
+//! ## Chat context
+//!
+//! By default,
+//! chat context is managed by truncating the chat in some situations where
 //! we're confident we're only deleting irrelevant information.
 //! This is a conservative approach,
 //! so it might sometimes fail.
 //! If you notice issues with the chat context,
 //! please [file an issue](https://github.com/schneiderfelipe/cligpt/issues/new) so we can address it.
 //!
+//! Other strategies can be selected with `--context-strategy`:
+//!
+//! - `similarity` (default): truncate at the message least similar to the
+//!   last exchange,
+//!   as long as it comes before the most similar one.
+//! - `sliding-window:N`: keep only the last `N` messages or so,
+//!   since answers are kept or dropped together with their questions.
+//! - `token-budget`: keep as many recent messages as fit in the context
+//!   window.
+//! - `summarize`: like `token-budget`,
+//!   but condense the messages truncated into a running summary
+//!   (see `--summarize` below).
+//! - `none`: never truncate the chat.
+//!
+//! Regardless of the strategy,
+//! the oldest exchanges (questions together with their answers) are dropped
+//! whenever the chat wouldn't fit in the model's context window anymore,
+//! keeping `--reserved-tokens` tokens free for the answer.
+//! Context windows of the official models are known;
+//! for other models,
+//! pass `--context-window`.
+//!
+//! Give a session a system prompt with `--system`,
+//! either as text or as `@file` to read it from a file.
+//! It is sent at the head of every request until changed
+//! (pass an empty string to remove it).
+//! Prompts you use often can be kept as personas in your configuration
+//! directory
+//! (e.g., `~/.config/cligpt/personas` on Linux):
+//!
+//! ```sh
+//! $ cligpt persona add reviewer "You are a meticulous Rust code reviewer."
+//! $ cligpt persona list
+//! $ cligpt persona use reviewer
+//! ```
+//!
+//! To see which messages would be sent along with your next one,
+//! how similar each is to the last exchange,
+//! where the chat is cut and why,
+//! and how many tokens it all takes,
+//! run `cligpt context`
+//! (with the same options you chat with).
+//!
+//! Pass `--summarize` to have truncated messages condensed by the model into
+//! a running summary of the conversation so far
+//! (whatever the strategy truncating them),
+//! which is sent along with the chat from then on,
+//! so that long-running sessions keep their gist.
+//! The summary is shown at the top of `cligpt show`.
+//!
+//! Messages that must always stay in the chat context,
+//! such as a project description,
+//! can be pinned when sending them with `--pin`,
+//! or later by their index as shown by `cligpt context`:
+//!
+//! ```sh
+//! $ cligpt pin 0 1
+//! $ cligpt unpin 1
+//! ```
+//!
+//! Pinned messages are never truncated by any strategy,
+//! nor dropped to fit the context window.
+//!
+//! Truncated messages are never lost, though:
+//! they are archived with the session and can be viewed using
+//! `cligpt show --all`.
+//! Pass `--recall K` to send the `K` archived messages most similar to your
+//! new one along with the chat,
+//! so that old but relevant information is not forgotten.
+//! Add `--recall-all-sessions` to also recall messages from your other
+//! sessions.
+//!
+//! Similarity-based features rely on embeddings of your messages,
+//! computed with `text-embedding-ada-002` by default.
+//! Pick another model of the API with `--embedding-model`
+//! (and its `--embedding-dimension`),
+//! use `--embedding-model offline` for cheap embeddings computed locally
+//! (a hashed bag-of-words),
+//! or `--embedding-model none` to not embed messages at all,
+//! e.g. for endpoints without embeddings.
+//! Without embeddings,
+//! the `similarity` strategy falls back to `token-budget`,
+//! and `--recall` and searching by meaning are unavailable.
+//!
+//! Chat sessions are stored in your data directory
+//! (e.g., `~/.local/share/cligpt` on Linux),
+//! and embeddings of messages are cached in your cache directory
+//! (e.g., `~/.cache/cligpt` on Linux),
+//! so that the same text is never embedded twice.
+//! Every change is written to a temporary file first and only then moved
+//! into place,
+//! and the previous version is kept as a backup,
+//! so an interrupted `cligpt` never leaves a broken chat history behind.
+//!
+//! ## Searching
+//!
+//! Find old answers with `cligpt search`,
+//! which ranks the messages of all your sessions by how similar their
+//! meaning is to your query:
+//!
+//! ```sh
+//! $ cligpt search "how to reverse a list in python"
+//! ```
+//!
+//! Pass `--text` to look for the query itself (ignoring case) instead,
+//! or `--regex` to look for a (case-insensitive) regular expression,
+//! both of which work offline,
+//! and `--session` to only search a single session.
+//!
+//! ## Configuration
+//!
+//! Defaults for most options can be kept in a configuration file,
+//! `cligpt config edit` opens it in your editor
+//! and `cligpt config path` shows where it is
+//! (e.g., `~/.config/cligpt/config.toml` on Linux).
+//! Options are written as in the command line,
+//! without the leading dashes:
+//!
+//! ```toml
+//! model = "gpt4"
+//! temperature = 0.5
+//! api-key = "sk-..."
+//!
+//! [profile.local]
+//! api-base = "http://localhost:8080/v1"
+//! model = "llama"
+//! embedding-model = "offline"
+//! ```
+//!
+//! Settings of a named profile are only used when it is selected
+//! with `--profile local` (or the `CLIGPT_PROFILE` environment variable).
+//! A `.cligpt.toml` in the current directory (or any of its parents)
+//! holds settings for a single project,
+//! and can be edited with `cligpt config edit --project`.
+//! `cligpt config show` shows the settings of both files together.
+//!
+//! Since anyone can put a `.cligpt.toml` in a repository,
+//! those files can't set `api-key`, `api-base`
+//! or a `system` prompt read from a file (`@file`),
+//! unless you trust the project in your own configuration file:
+//!
+//! ```toml
+//! trusted-projects = ["/home/me/code/my-project"]
+//! ```
+//!
+//! Each setting is taken from the first place it is found in:
+//!
+//! 1. the command line,
+//! 2. environment variables (`OPENAI_API_KEY` and `OPENAI_API_BASE`),
+//! 3. the selected profile of `.cligpt.toml`,
+//! 4. the rest of `.cligpt.toml`,
+//! 5. the selected profile of your configuration file,
+//! 6. the rest of your configuration file.
+//!
+//! A `system` prompt in the configuration
+//! (where `@file` paths are relative to the configuration file)
+//! is only given to sessions that haven't started yet (and have no persona),
+//! whereas `--system` replaces the prompt of the current session.
+//!
 //! For more information on available options,
 //! run `cligpt --help`.
 //!
@@ -190,11 +464,16 @@
 //!
 //! `cligpt` is released under the [MIT License](LICENSE).
 
+use std::env;
+use std::ffi::OsString;
 use std::fmt::Write as _;
 use std::fs;
+use std::future::Future;
 use std::io;
+use std::io::IsTerminal as _;
 use std::io::Read;
 use std::io::Write;
+use std::mem;
 use std::ops::RangeInclusive;
 use std::path::Path;
 
@@ -202,56 +481,398 @@ use async_openai::types::ChatCompletionRequestMessage;
 use async_openai::types::ChatCompletionRequestMessageArgs;
 use async_openai::types::ChatCompletionResponseStream;
 use async_openai::types::CreateChatCompletionRequestArgs;
-use async_openai::types::CreateEmbeddingRequestArgs;
 use async_openai::types::Role;
 use async_openai::Client;
+use async_openai::API_BASE;
+use cache::EmbeddingCache;
+use clap::error::ContextKind;
+use clap::error::ContextValue;
+use clap::error::ErrorKind;
+use clap::parser::ValueSource;
+use clap::ArgMatches;
+use clap::CommandFactory as _;
+use clap::FromArgMatches as _;
 use clap::Parser;
 use clap::Subcommand;
-use clap::ValueEnum;
 use color_eyre::eyre;
 use color_eyre::eyre::Context;
+use config::ConfigFiles;
+use context::Strategy;
 use directories::ProjectDirs;
+use editor::Draft;
+use embedder::Embedder;
+use embedder::EmbeddingModel;
+use embedding::PackedEmbedding;
+use futures_util::future;
 use futures_util::StreamExt;
+use history::read_chat_from_path;
+use history::write_chat_to_path;
+use history::Chat;
+use history::ChatEntry;
+use history::ChatMetadata;
+use persona::Personas;
+use repl::Input;
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+use session::Session;
+use session::Sessions;
+use tokens::TokenBudget;
+
+mod archive;
+mod attachment;
+mod cache;
+mod config;
+mod context;
+mod editor;
+mod embedder;
+mod embedding;
+mod history;
+mod persona;
+mod recall;
+mod repl;
+mod search;
+mod session;
+mod summary;
+mod tokens;
 
 const API_KEY_RANGE: RangeInclusive<usize> = 40..=50;
 const TEMPERATURE_RANGE: RangeInclusive<f32> = 0.0..=1.0;
 
-const EMBEDDING_LENGTH: usize = 1536;
-
 type Embedding = Vec<f32>;
-type EmbeddedMessage = (ChatCompletionRequestMessage, Embedding);
 
 /// A command-line interface to talk to `ChatGPT`.
 #[derive(Debug, Parser)]
 #[command(version, author, about)]
 struct Cli {
     /// Command to perform if not chatting with the AI.
+    ///
+    /// Without one,
+    /// a message is read from the standard input and answered,
+    /// or an interactive chat starts if the standard input is a terminal.
     #[command(subcommand)]
     command: Option<Command>,
 
+    /// Message to send,
+    /// followed by anything piped into `cligpt` as context.
+    ///
+    /// Without it,
+    /// the message is read from the standard input.
+    /// Put `--` before a message starting with the name of a command.
+    #[arg(conflicts_with = "edit")]
+    message: Vec<String>,
+
+    /// Attach a file to the message,
+    /// or all files matching a glob (e.g., `'src/**/*.rs'`).
+    ///
+    /// Can be given multiple times.
+    /// Binary, non-UTF-8 and overly large files are skipped.
+    /// Without a message (typed or piped), the files are sent on their own.
+    #[arg(short, long = "file", value_name = "PATH|GLOB")]
+    files: Vec<String>,
+
     /// Model to use for the chat.
-    #[arg(long, value_enum, default_value_t = Default::default())]
+    ///
+    /// Either one of the aliases `gpt35` (`gpt-3.5-turbo`) and `gpt4`
+    /// (`gpt-4`),
+    /// or any model identifier understood by the API
+    /// (run `cligpt models` to list them).
+    #[arg(long, global = true, default_value = "gpt35", value_parser = model_parser)]
     model: Model,
 
     /// Temperature to use for the chat.
-    #[arg(long, default_value_t = 0.7, value_parser = temperature_parser)]
+    #[arg(long, global = true, default_value_t = 0.7, value_parser = temperature_parser)]
     temperature: f32,
 
+    /// Size of the model's context window in tokens.
+    ///
+    /// Known for the official models,
+    /// but required for other models with windows larger than 4096 tokens.
+    #[arg(long, global = true)]
+    context_window: Option<usize>,
+
+    /// Number of tokens of the context window to reserve for the answer.
+    #[arg(long, global = true, default_value_t = 512)]
+    reserved_tokens: usize,
+
+    /// How to decide which messages stay in the chat context.
+    ///
+    /// One of `similarity`, `sliding-window:N` (keep the last N messages),
+    /// `token-budget` (keep as many messages as fit in the context window),
+    /// `summarize` (like `token-budget`, but see `--summarize`) or `none`.
+    /// The chat is always cut to fit the context window before sending it.
+    #[arg(
+        long,
+        global = true,
+        default_value_t = Strategy::default(),
+        value_parser = context::strategy_parser
+    )]
+    context_strategy: Strategy,
+
+    /// Condense messages truncated from the chat into a running summary,
+    /// which is sent along with the chat from then on.
+    #[arg(long, global = true, overrides_with = "no_summarize")]
+    summarize: bool,
+
+    /// Don't condense truncated messages,
+    /// even if the configuration or the `summarize` context strategy says to.
+    #[arg(long, global = true, overrides_with = "summarize")]
+    no_summarize: bool,
+
+    /// System prompt of the session,
+    /// either as text or as `@file` to read it from a file.
+    ///
+    /// It is kept at the head of every request until changed.
+    /// Pass an empty string to remove it.
+    #[arg(long, value_parser = persona::prompt_parser)]
+    system: Option<String>,
+
+    /// System prompt of the configuration,
+    /// given only to sessions that haven't started yet.
+    #[arg(skip)]
+    default_system: Option<String>,
+
+    /// Pin the message,
+    /// so that it always stays in the chat context.
+    ///
+    /// In interactive mode, only the first message is pinned.
+    #[arg(long)]
+    pin: bool,
+
+    /// Write the message in your editor (`$VISUAL` or `$EDITOR`)
+    /// instead of reading it from the standard input.
+    ///
+    /// Pass `--edit=last` to start from your previous message,
+    /// or `--edit=@file` to start from a template.
+    /// Leaving the message empty aborts.
+    #[arg(
+        short,
+        long,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "",
+        value_parser = editor::draft_parser
+    )]
+    edit: Option<Draft>,
+
+    /// Number of archived messages most similar to the new one to send
+    /// along with the chat.
+    #[arg(long, global = true, default_value_t = 0)]
+    recall: usize,
+
+    /// Recall messages from every session instead of only the current one.
+    ///
+    /// Requires `--recall` (or `recall` in the configuration).
+    #[arg(long, global = true)]
+    recall_all_sessions: bool,
+
+    /// How to embed messages for similarity-based features.
+    ///
+    /// Either an embedding model offered by the API,
+    /// `offline` (a cheap hashed bag-of-words computed locally)
+    /// or `none`.
+    /// Without embeddings,
+    /// the `similarity` context strategy falls back to `token-budget`.
+    #[arg(
+        long,
+        global = true,
+        default_value = embedder::DEFAULT_EMBEDDING_MODEL,
+        value_parser = embedder::embedding_model_parser
+    )]
+    embedding_model: EmbeddingModel,
+
+    /// Dimension of the embeddings.
+    ///
+    /// Checked for models of the API,
+    /// where it is known for the default model only.
+    /// Defaults to 512 for `offline` embeddings.
+    #[arg(long, global = true, value_parser = embedder::embedding_dimension_parser)]
+    embedding_dimension: Option<usize>,
+
     /// Your OpenAI API key.
-    #[arg(short = 'k', long, value_parser = api_key_parser, env = "OPENAI_API_KEY")]
-    api_key: String,
+    ///
+    /// Only required when talking to the OpenAI API itself.
+    #[arg(short = 'k', long, global = true, env = "OPENAI_API_KEY")]
+    api_key: Option<String>,
+
+    /// Base URL of an OpenAI-compatible API to talk to.
+    #[arg(
+        long,
+        global = true,
+        default_value = API_BASE,
+        value_parser = api_base_parser,
+        env = "OPENAI_API_BASE"
+    )]
+    api_base: String,
+
+    /// Chat session to use instead of the current one.
+    #[arg(long, global = true, value_parser = session::session_name_parser, env = "CLIGPT_SESSION")]
+    session: Option<String>,
+
+    /// Profile of the configuration files to use.
+    #[arg(long, global = true, env = "CLIGPT_PROFILE")]
+    profile: Option<String>,
+
+    /// Wait for other `cligpt` processes using the same session to finish
+    /// instead of failing.
+    #[arg(long)]
+    wait: bool,
 }
 
 #[derive(Debug, Subcommand)]
 enum Command {
     /// Show a chat.
     #[command(alias = "s")]
+    Show {
+        /// Also show messages that were truncated from the chat context.
+        #[arg(short, long)]
+        all: bool,
+    },
+
+    /// Manage chat sessions.
+    Sessions {
+        #[command(subcommand)]
+        command: SessionsCommand,
+    },
+
+    /// Show which messages would be sent along with the next one,
+    /// and why the others aren't.
+    Context,
+
+    /// Pin messages of the chat by their index (as shown by `cligpt context`),
+    /// so that they always stay in the chat context.
+    Pin {
+        #[arg(required = true)]
+        indices: Vec<usize>,
+    },
+
+    /// Unpin messages of the chat by their index.
+    Unpin {
+        #[arg(required = true)]
+        indices: Vec<usize>,
+    },
+
+    /// Manage personas,
+    /// reusable system prompts.
+    Persona {
+        #[command(subcommand)]
+        command: PersonaCommand,
+    },
+
+    /// Chat interactively,
+    /// with line editing and history.
+    ///
+    /// Started by default when the standard input is a terminal.
+    #[command(alias = "r")]
+    Repl,
+
+    /// List the models offered by the API.
+    Models,
+
+    /// Manage configuration files.
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+
+    /// Search the messages of all sessions,
+    /// or only of the one given with `--session`.
+    Search {
+        /// What to look for.
+        query: String,
+
+        /// Match messages containing the query (ignoring case)
+        /// instead of by meaning.
+        ///
+        /// Doesn't require access to the API.
+        #[arg(long)]
+        text: bool,
+
+        /// Match messages containing the query as a (case-insensitive)
+        /// regular expression.
+        ///
+        /// Implies `--text`.
+        #[arg(long)]
+        regex: bool,
+
+        /// Maximum number of messages to show.
+        #[arg(short = 'n', long, default_value_t = 10)]
+        limit: usize,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum SessionsCommand {
+    /// List all sessions, marking the current one.
+    #[command(alias = "ls")]
+    List,
+
+    /// Create a new session and switch to it.
+    New { name: String },
+
+    /// Rename a session.
+    #[command(alias = "mv")]
+    Rename { from: String, to: String },
+
+    /// Delete a session and its chat history.
+    #[command(alias = "rm")]
+    Delete { name: String },
+
+    /// Make a session the current one.
+    Switch { name: String },
+}
+
+#[derive(Debug, Subcommand)]
+enum ConfigCommand {
+    /// Show the settings read from configuration files,
+    /// with the selected profile applied.
     Show,
+
+    /// Edit the configuration file of the user.
+    Edit {
+        /// Edit the configuration file of the current project instead.
+        #[arg(long)]
+        project: bool,
+    },
+
+    /// Show where configuration files are read from.
+    Path,
+}
+
+#[derive(Debug, Subcommand)]
+enum PersonaCommand {
+    /// List all personas,
+    /// marking the one used by the current session.
+    #[command(alias = "ls")]
+    List,
+
+    /// Add a persona.
+    Add {
+        #[arg(value_parser = persona::persona_name_parser)]
+        name: String,
+
+        /// System prompt of the persona,
+        /// either as text or as `@file` to read it from a file.
+        #[arg(value_parser = persona::prompt_parser)]
+        prompt: String,
+    },
+
+    /// Remove a persona.
+    #[command(alias = "rm")]
+    Remove {
+        #[arg(value_parser = persona::persona_name_parser)]
+        name: String,
+    },
+
+    /// Use a persona as the system prompt of the current session.
+    Use {
+        #[arg(value_parser = persona::persona_name_parser)]
+        name: String,
+    },
 }
 
 /// Different language models that can be used for natural language processing
 /// tasks.
-#[derive(Clone, Copy, Debug, Default, ValueEnum)]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
 enum Model {
     /// A highly capable GPT-3.5 model optimized for chat at a reduced cost.
     #[default]
@@ -260,18 +881,35 @@ enum Model {
     /// A more capable model than any GPT-3.5,
     /// designed for complex tasks and optimized for chat.
     Gpt4,
+
+    /// Any other model identifier understood by the API,
+    /// such as dated snapshots, fine-tuned or local models.
+    Other(String),
 }
 
 impl Model {
     #[inline]
-    const fn name(self) -> &'static str {
+    fn name(&self) -> &str {
         match self {
             Self::Gpt35 => "gpt-3.5-turbo",
             Self::Gpt4 => "gpt-4",
+            Self::Other(name) => name,
         }
     }
 }
 
+#[inline]
+fn model_parser(model: &str) -> eyre::Result<Model> {
+    let model = model.trim();
+    eyre::ensure!(!model.is_empty(), "cannot use empty string as model name");
+
+    Ok(match model {
+        "gpt35" => Model::Gpt35,
+        "gpt4" => Model::Gpt4,
+        _ => Model::Other(model.into()),
+    })
+}
+
 #[inline]
 fn temperature_parser(temperature: &str) -> eyre::Result<f32> {
     let temperature: f32 = temperature.parse()?;
@@ -326,27 +964,430 @@ fn api_key_parser(api_key: &str) -> eyre::Result<String> {
     Ok(api_key.into())
 }
 
+#[inline]
+fn api_base_parser(api_base: &str) -> eyre::Result<String> {
+    let api_base = api_base.trim();
+    eyre::ensure!(!api_base.is_empty(), "cannot use empty string as API base");
+    eyre::ensure!(
+        api_base.starts_with("http://") || api_base.starts_with("https://"),
+        "'{api_base}' is not an HTTP(S) URL"
+    );
+
+    Ok(api_base.trim_end_matches('/').into())
+}
+
+impl Cli {
+    /// Build an API client for the configured endpoint.
+    ///
+    /// API keys are only validated for the official `OpenAI` API,
+    /// since other endpoints use their own formats (or no key at all).
+    #[inline]
+    fn client(&self) -> eyre::Result<Client> {
+        let api_key = if self.api_base == API_BASE {
+            let Some(api_key) = &self.api_key else {
+                eyre::bail!("an OpenAI API key is required (pass --api-key or set OPENAI_API_KEY)");
+            };
+            api_key_parser(api_key).context("invalid OpenAI API key")?
+        } else {
+            self.api_key.clone().unwrap_or_default()
+        };
+
+        Ok(Client::new()
+            .with_api_key(api_key)
+            .with_api_base(&self.api_base))
+    }
+
+    /// Token budget of the selected model.
+    #[inline]
+    fn budget(&self) -> TokenBudget {
+        TokenBudget::for_model(self.model.name(), self.context_window, self.reserved_tokens)
+    }
+
+    /// Whether messages truncated are condensed into the summary.
+    #[inline]
+    fn summarizes(&self) -> bool {
+        !self.no_summarize && (self.summarize || self.strategy().summarizes())
+    }
+
+    /// The context strategy to use with the selected embeddings.
+    #[inline]
+    fn strategy(&self) -> Strategy {
+        // Without embeddings, every message looks equally (dis)similar.
+        match self.context_strategy {
+            Strategy::Similarity if self.embedding_model == EmbeddingModel::None => {
+                Strategy::TokenBudget
+            }
+            strategy => strategy,
+        }
+    }
+
+    /// Build the embedder selected with `--embedding-model`.
+    #[inline]
+    fn embedder(&self, cache: EmbeddingCache) -> eyre::Result<Embedder> {
+        Ok(match &self.embedding_model {
+            EmbeddingModel::Api(model) => Embedder::Api {
+                client: Box::new(self.client()?),
+                cache,
+                model: model.clone(),
+                dimension: self.embedding_dimension.or_else(|| {
+                    (model == embedder::DEFAULT_EMBEDDING_MODEL)
+                        .then_some(embedder::DEFAULT_EMBEDDING_DIMENSION)
+                }),
+            },
+            EmbeddingModel::Offline => Embedder::Offline {
+                dimension: self
+                    .embedding_dimension
+                    .unwrap_or(embedder::DEFAULT_OFFLINE_EMBEDDING_DIMENSION),
+            },
+            EmbeddingModel::None => Embedder::None,
+        })
+    }
+
+    /// Take settings from configuration files,
+    /// unless given as flags or environment variables.
+    #[inline]
+    fn apply_config(
+        &mut self,
+        settings: config::Settings,
+        matches: &ArgMatches,
+    ) -> eyre::Result<()> {
+        let is_unset = |id: &str| {
+            !matches!(
+                matches.value_source(id),
+                Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+            )
+        };
+        let config::Settings {
+            model,
+            temperature,
+            api_key,
+            api_base,
+            context_window,
+            reserved_tokens,
+            context_strategy,
+            summarize,
+            recall,
+            embedding_model,
+            embedding_dimension,
+            system,
+            trusted_projects: _,
+            profile: _,
+        } = settings;
+
+        if let Some(model) = model.filter(|_| is_unset("model")) {
+            self.model = model_parser(&model).context("invalid model")?;
+        }
+        if let Some(temperature) = temperature.filter(|_| is_unset("temperature")) {
+            self.temperature =
+                temperature_parser(&temperature.to_string()).context("invalid temperature")?;
+        }
+        if let Some(api_key) = api_key.filter(|_| is_unset("api_key")) {
+            self.api_key = Some(api_key);
+        }
+        if let Some(api_base) = api_base.filter(|_| is_unset("api_base")) {
+            self.api_base = api_base_parser(&api_base).context("invalid API base")?;
+        }
+        if let Some(context_window) = context_window.filter(|_| is_unset("context_window")) {
+            self.context_window = Some(context_window);
+        }
+        if let Some(reserved_tokens) = reserved_tokens.filter(|_| is_unset("reserved_tokens")) {
+            self.reserved_tokens = reserved_tokens;
+        }
+        if let Some(strategy) = context_strategy.filter(|_| is_unset("context_strategy")) {
+            self.context_strategy =
+                context::strategy_parser(&strategy).context("invalid context strategy")?;
+        }
+        if let Some(summarize) =
+            summarize.filter(|_| is_unset("summarize") && is_unset("no_summarize"))
+        {
+            self.summarize = summarize;
+        }
+        if let Some(recall) = recall.filter(|_| is_unset("recall")) {
+            self.recall = recall;
+        }
+        if let Some(model) = embedding_model.filter(|_| is_unset("embedding_model")) {
+            self.embedding_model =
+                embedder::embedding_model_parser(&model).context("invalid embedding model")?;
+        }
+        if let Some(dimension) = embedding_dimension.filter(|_| is_unset("embedding_dimension")) {
+            self.embedding_dimension = Some(
+                embedder::embedding_dimension_parser(&dimension.to_string())
+                    .context("invalid embedding dimension")?,
+            );
+        }
+        if let Some(system) = system.filter(|_| is_unset("system")) {
+            self.default_system =
+                Some(persona::prompt_parser(&system).context("invalid system prompt")?);
+        }
+        Ok(())
+    }
+
+    /// The message to send,
+    /// or nothing if writing it was aborted.
+    #[inline]
+    fn message(&self, session: &Session) -> eyre::Result<Option<String>> {
+        // Read files first, so that problems show up before any editing.
+        let files = attachment::attach_files(&self.files).context("failed to attach files")?;
+
+        let message = if let Some(draft) = &self.edit {
+            let message = compose_message(session, draft)?;
+            if message.trim().is_empty() {
+                eprintln!("aborting due to empty message");
+                return Ok(None);
+            }
+            message
+        } else {
+            // Attached files can make up the whole message,
+            // so there may be nothing left to type.
+            let typed = self.message.is_empty() && self.files.is_empty();
+            let input = if typed || !io::stdin().is_terminal() {
+                read_message_from_stdin()
+                    .context("failed to read message from the standard input")?
+            } else {
+                String::new()
+            };
+            if self.message.is_empty() {
+                input
+            } else {
+                attachment::with_input(&self.message.join(" "), &input)
+            }
+        };
+
+        Ok(Some(
+            std::iter::once(message)
+                .filter(|message| !message.trim().is_empty())
+                .chain(files)
+                .collect::<Vec<_>>()
+                .join("\n\n"),
+        ))
+    }
+
+    /// Settings for chatting in `session`.
+    #[inline]
+    fn chat_settings(&self, sessions: &Sessions, session: &Session) -> eyre::Result<ChatSettings> {
+        eyre::ensure!(
+            self.recall == 0 || self.embedding_model != EmbeddingModel::None,
+            "recalling messages requires embeddings (cannot use --recall with --embedding-model \
+             none)"
+        );
+        // Checked here rather than by clap,
+        // since `recall` may come from the configuration.
+        eyre::ensure!(
+            self.recall > 0 || !self.recall_all_sessions,
+            "--recall-all-sessions requires recalling messages (pass --recall N or set recall in \
+             the configuration)"
+        );
+        let recall_from = if self.recall_all_sessions {
+            sessions
+                .list()
+                .context("failed to list sessions")?
+                .into_iter()
+                .filter(|name| name != session.name())
+                .map(|name| sessions.session(&name))
+                .collect::<eyre::Result<_>>()?
+        } else {
+            Vec::new()
+        };
+        Ok(ChatSettings {
+            model: self.model.clone(),
+            temperature: self.temperature,
+            context_window: self.context_window,
+            reserved_tokens: self.reserved_tokens,
+            strategy: self.strategy(),
+            summarize: self.summarizes(),
+            system: self.system.clone(),
+            default_system: self.default_system.clone(),
+            pin: self.pin,
+            recall: self.recall,
+            recall_from,
+        })
+    }
+}
+
+/// Parse the command line,
+/// taking free text starting with the name of a command
+/// (e.g., `cligpt show me how`) for a message
+/// when it doesn't parse as that command.
+///
+/// Only commands without positional arguments,
+/// followed by several words and no options,
+/// are taken for a message.
+/// Anything else is reported as a usage error.
+#[inline]
+fn parse_args(args: Vec<OsString>) -> Result<ArgMatches, clap::Error> {
+    let command = Cli::command();
+    let error = match command.clone().try_get_matches_from(&args) {
+        Ok(matches) => return Ok(matches),
+        Err(error) => error,
+    };
+    // Only words that don't belong to the command at all,
+    // as opposed to misspelled options, tell a message apart.
+    let unexpected_word = matches!(
+        error.get(ContextKind::InvalidArg),
+        Some(ContextValue::String(arg)) if !arg.starts_with('-')
+    );
+    if error.kind() != ErrorKind::UnknownArgument || !unexpected_word {
+        return Err(error);
+    }
+
+    let Some((n, subcommand)) = args
+        .iter()
+        .enumerate()
+        .skip(1)
+        .find_map(|(n, arg)| Some((n, command.find_subcommand(arg)?)))
+    else {
+        return Err(error);
+    };
+    // Anything but a few words after a command with no arguments of its own
+    // is more likely a mistake than the start of a message.
+    let words = &args[n + 1..];
+    let given_options = words
+        .iter()
+        .any(|word| word.to_string_lossy().starts_with('-'));
+    if subcommand.has_subcommands()
+        || subcommand.get_positionals().next().is_some()
+        || given_options
+        || words.len() < 2
+    {
+        return Err(error);
+    }
+
+    let mut args = args;
+    args.insert(n, "--".into());
+    command.try_get_matches_from(args).map_err(|_| error)
+}
+
 #[tokio::main]
 async fn main() -> eyre::Result<()> {
     color_eyre::install().context("failed to install error report handler")?;
 
-    let cli = Cli::parse();
+    let matches = parse_args(env::args_os().collect()).unwrap_or_else(|error| error.exit());
+    let mut cli = Cli::from_arg_matches(&matches).unwrap_or_else(|error| error.exit());
 
-    let path = {
-        let Some(proj_dirs) = ProjectDirs::from("com", "schneiderfelipe", "cligpt") else {
-            eyre::bail!("failed to obtain project directory");
+    let Some(proj_dirs) = ProjectDirs::from("com", "schneiderfelipe", "cligpt") else {
+        eyre::bail!("failed to obtain project directory");
+    };
+    let config_files = ConfigFiles::find(proj_dirs.config_dir())?;
+    // A broken configuration file shouldn't keep anyone from fixing it.
+    if !matches!(cli.command, Some(Command::Config { .. })) {
+        let settings = config_files
+            .load(cli.profile.as_deref())
+            .context("failed to read the configuration")?;
+        cli.apply_config(settings, &matches)
+            .context("invalid configuration")?;
+    }
+    let sessions = {
+        let data_dir = proj_dirs.data_dir();
+        fs::create_dir_all(data_dir).context("failed to create the data directory")?;
+        let sessions = Sessions::open(data_dir)?;
+        // Older versions kept a single chat history in the cache directory.
+        sessions
+            .migrate_legacy(proj_dirs.cache_dir().join("chat.json"))
+            .context("failed to migrate the legacy chat history")?;
+        sessions
+    };
+    let session = {
+        let name = match &cli.session {
+            Some(name) => name.clone(),
+            None => sessions
+                .current()
+                .context("failed to obtain the current session")?,
         };
-        let cache_dir = proj_dirs.cache_dir();
-        fs::create_dir_all(cache_dir).context("failed to create the cache directory")?;
-        cache_dir.join("chat.json")
+        sessions.session(&name)?
     };
-
-    if let Some(command) = cli.command {
+    let cache = EmbeddingCache::open(proj_dirs.cache_dir().join("embeddings"));
+    let personas = Personas::open(proj_dirs.config_dir().join("personas"));
+
+    if cli.command.is_none()
+        && cli.message.is_empty()
+        && cli.files.is_empty()
+        && cli.edit.is_none()
+        && io::stdin().is_terminal()
+    {
+        cli.command = Some(Command::Repl);
+    }
+    if let Some(command) = cli.command.take() {
         match command {
-            Command::Show => handle_show(path).context("failed to handle the show command")?,
+            Command::Show { all } => {
+                handle_show(&session, all).context("failed to handle the show command")?;
+            }
+            Command::Sessions { command } => handle_sessions(&sessions, command, cli.wait)
+                .context("failed to handle the sessions command")?,
+            Command::Persona { command } => {
+                handle_persona(&personas, &session, command, cli.wait)
+                    .context("failed to handle the persona command")?;
+            }
+            Command::Context => handle_context(&session, &cli.budget(), cli.strategy())
+                .context("failed to handle the context command")?,
+            Command::Pin { indices } => handle_pin(&session, &indices, true, cli.wait)
+                .context("failed to handle the pin command")?,
+            Command::Unpin { indices } => handle_pin(&session, &indices, false, cli.wait)
+                .context("failed to handle the unpin command")?,
+            Command::Repl => {
+                let client = cli.client()?;
+                let embedder = cli.embedder(cache)?;
+                let mut settings = cli.chat_settings(&sessions, &session)?;
+                let history_path = proj_dirs.data_dir().join("repl_history");
+                handle_repl(
+                    &client,
+                    &embedder,
+                    &mut settings,
+                    &session,
+                    &history_path,
+                    cli.wait,
+                )
+                .await
+                .context("failed to handle the repl command")?;
+            }
+            Command::Config { command } => {
+                handle_config(&config_files, cli.profile.as_deref(), command)
+                    .context("failed to handle the config command")?;
+            }
+            Command::Models => {
+                let client = cli.client()?;
+                handle_models(&client)
+                    .await
+                    .context("failed to handle the models command")?;
+            }
+            Command::Search {
+                query,
+                text,
+                regex,
+                limit,
+            } => {
+                let searched = if cli.session.is_some() {
+                    vec![session]
+                } else {
+                    sessions
+                        .list()
+                        .context("failed to list sessions")?
+                        .into_iter()
+                        .map(|name| sessions.session(&name))
+                        .collect::<eyre::Result<_>>()?
+                };
+                let embedder = if text || regex {
+                    None
+                } else {
+                    let embedder = cli.embedder(cache)?;
+                    eyre::ensure!(
+                        !embedder.is_none(),
+                        "searching by meaning requires embeddings (pass --text to search by text)"
+                    );
+                    Some(embedder)
+                };
+                handle_search(embedder.as_ref(), &searched, &query, regex, limit)
+                    .await
+                    .context("failed to handle the search command")?;
+            }
         }
     } else {
-        handle_chat(cli.model, cli.temperature, cli.api_key, path)
+        let client = cli.client()?;
+        let embedder = cli.embedder(cache)?;
+        let settings = cli.chat_settings(&sessions, &session)?;
+        let Some(message) = cli.message(&session)? else {
+            return Ok(());
+        };
+        handle_chat(&client, &embedder, &settings, &session, &message, cli.wait)
             .await
             .context("failed to handle the chat command")?;
     }
@@ -354,6 +1395,132 @@ async fn main() -> eyre::Result<()> {
     Ok(())
 }
 
+/// Settings for chatting with the AI.
+struct ChatSettings {
+    model: Model,
+    temperature: f32,
+    context_window: Option<usize>,
+    reserved_tokens: usize,
+    strategy: Strategy,
+    summarize: bool,
+    system: Option<String>,
+    default_system: Option<String>,
+    pin: bool,
+    recall: usize,
+    recall_from: Vec<Session>,
+}
+
+impl ChatSettings {
+    /// Token budget of the selected model,
+    /// which changes along with it.
+    #[inline]
+    fn budget(&self) -> TokenBudget {
+        TokenBudget::for_model(self.model.name(), self.context_window, self.reserved_tokens)
+    }
+}
+
+/// Messages always sent before the chat itself.
+#[inline]
+fn preamble(
+    metadata: &ChatMetadata,
+    recalled: Option<&ChatCompletionRequestMessage>,
+) -> eyre::Result<Vec<ChatCompletionRequestMessage>> {
+    let system = metadata.system.as_deref().map(persona::system_message);
+    let summary = metadata.summary.as_deref().map(summary::summary_message);
+    let mut preamble: Vec<_> = system
+        .into_iter()
+        .chain(summary)
+        .collect::<eyre::Result<_>>()?;
+    preamble.extend(recalled.cloned());
+    Ok(preamble)
+}
+
+/// `embedding`,
+/// or an empty one if computing it failed.
+///
+/// Embeddings are computed once the answer is shown,
+/// so failing to compute them must not lose the exchange.
+/// Messages without embeddings are left out of similarity comparisons
+/// (see [`context::Similarity::analyze`]).
+#[inline]
+fn embedding_or_warning(embedding: eyre::Result<Embedding>) -> PackedEmbedding {
+    match embedding {
+        Ok(embedding) => embedding.into(),
+        Err(error) => {
+            eprintln!("warning: {error:#}");
+            PackedEmbedding::default()
+        }
+    }
+}
+
+/// Drop the oldest messages of `chat` until it fits `budget` along with its
+/// preamble,
+/// folding them into the summary with `summarizer` (if any).
+/// Returns the messages dropped.
+///
+/// An updated summary may take more tokens than the previous one,
+/// so this repeats until nothing more needs to be dropped.
+/// If summarizing fails,
+/// the rest is dropped without being summarized.
+#[inline]
+async fn fit_chat<F, Fut>(
+    chat: &mut Chat,
+    budget: &TokenBudget,
+    recalled: Option<&ChatCompletionRequestMessage>,
+    mut summarizer: Option<F>,
+) -> eyre::Result<Vec<ChatEntry>>
+where
+    F: FnMut(Option<String>, Vec<ChatEntry>) -> Fut,
+    Fut: Future<Output = eyre::Result<String>>,
+{
+    let mut outdated_messages = Vec::new();
+    loop {
+        let (current_chat, outdated_chat) = tokens::fit_chat_to_budget(
+            mem::take(&mut chat.messages),
+            &budget.without(&preamble(&chat.metadata, recalled)?),
+        )
+        .context("failed to fit chat into the context window")?;
+        chat.messages = current_chat;
+        let Some(outdated_chat) = outdated_chat else {
+            break;
+        };
+
+        let Some(summarize) = &mut summarizer else {
+            outdated_messages.extend(outdated_chat);
+            break;
+        };
+        match summarize(chat.metadata.summary.clone(), outdated_chat.clone()).await {
+            Ok(summary) => chat.metadata.summary = Some(summary),
+            Err(error) => {
+                eprintln!("warning: {error:#}");
+                summarizer = None;
+            }
+        }
+        outdated_messages.extend(outdated_chat);
+    }
+    Ok(outdated_messages)
+}
+
+/// Split `chat` with `strategy` once the answer has been shown.
+///
+/// The answer may push the last exchange over `budget`,
+/// so if splitting fails the chat is kept whole rather than losing the
+/// exchange.
+#[inline]
+fn split_answered_chat(
+    strategy: Strategy,
+    budget: &TokenBudget,
+    chat: Vec<ChatEntry>,
+) -> context::Split {
+    match strategy.build(budget).split(chat.clone()) {
+        Ok(split) => split,
+        Err(error) => {
+            eprintln!("warning: {:#}", error.wrap_err("failed to split chat"));
+            (chat, None)
+        }
+    }
+}
+
 #[inline]
 fn read_message_from_stdin() -> eyre::Result<String> {
     let mut message = String::new();
@@ -364,6 +1531,26 @@ fn read_message_from_stdin() -> eyre::Result<String> {
     Ok(message)
 }
 
+/// Write a message in the editor of the user, starting from `draft`.
+#[inline]
+fn compose_message(session: &Session, draft: &Draft) -> eyre::Result<String> {
+    let initial = match draft {
+        Draft::Empty => String::new(),
+        Draft::LastMessage => {
+            let chat = read_chat_from_path(session.chat_path())
+                .context("failed to read the chat history")?;
+            chat.messages
+                .into_iter()
+                .rev()
+                .find(|entry| entry.message.role == Role::User)
+                .map(|entry| entry.message.content)
+                .unwrap_or_default()
+        }
+        Draft::Template(template) => template.clone(),
+    };
+    editor::edit(&initial).context("failed to compose message in the editor")
+}
+
 #[inline]
 async fn process_chat_response(stream: &mut ChatCompletionResponseStream) -> eyre::Result<String> {
     let mut stdout = io::stdout().lock();
@@ -372,7 +1559,7 @@ async fn process_chat_response(stream: &mut ChatCompletionResponseStream) -> eyr
     writeln!(stdout).context("failed to write new line to the standard output")?;
     while let Some(result) = stream.next().await {
         let response = result.context("failed to obtain a stream response")?;
-        if let Some(choice) = response.choices.get(0) {
+        if let Some(choice) = response.choices.first() {
             if let Some(text) = &choice.delta.content {
                 write!(stdout, "{text}")
                     .context("failed to write response delta to the standard output")?;
@@ -389,216 +1576,751 @@ async fn process_chat_response(stream: &mut ChatCompletionResponseStream) -> eyr
 }
 
 #[inline]
-fn handle_show(path: impl AsRef<Path>) -> eyre::Result<()> {
-    let chat = read_chat_from_path(path).context("failed to read the chat history")?;
+fn handle_show(session: &Session, all: bool) -> eyre::Result<()> {
+    write_chat(&mut io::stdout().lock(), session, all)
+        .context("failed to write the chat to the standard output")
+}
+
+/// Write a chat as shown by `cligpt show`.
+#[inline]
+fn write_chat(writer: &mut impl Write, session: &Session, all: bool) -> eyre::Result<()> {
+    let archive = if all {
+        archive::read_archive(session.archive_path()).context("failed to read the chat archive")?
+    } else {
+        Vec::new()
+    };
+    let chat =
+        read_chat_from_path(session.chat_path()).context("failed to read the chat history")?;
 
-    let mut stdout = io::stdout().lock();
-    for (message, _) in chat {
+    if let Some(system) = &chat.metadata.system {
+        writeln!(writer, "system:\n{system}\n").context("failed to write system prompt")?;
+    }
+    if let (false, Some(summary)) = (all, &chat.metadata.summary) {
+        writeln!(writer, "summary:\n{summary}\n").context("failed to write summary")?;
+    }
+    for (message, metadata) in archive
+        .into_iter()
+        .map(|archived| (archived.message, archived.metadata))
+        .chain(
+            chat.messages
+                .into_iter()
+                .map(|entry| (entry.message, entry.metadata)),
+        )
+    {
+        let pinned = if metadata.pinned { " (pinned)" } else { "" };
         if let Some(name) = message.name {
-            writeln!(stdout, "{name}:").context("failed to write name to the standard output")?;
+            writeln!(writer, "{name}{pinned}:").context("failed to write name")?;
+        } else {
+            writeln!(writer, "{name}{pinned}:", name = message.role)
+                .context("failed to write role")?;
+        }
+        writeln!(writer, "{}", message.content).context("failed to write content")?;
+        writeln!(writer).context("failed to write a new line")?;
+        writer.flush().context("failed to flush")?;
+    }
+
+    Ok(())
+}
+
+#[inline]
+fn handle_pin(session: &Session, indices: &[usize], pinned: bool, wait: bool) -> eyre::Result<()> {
+    let _lock = session.lock(wait)?;
+    let path = session.chat_path();
+    let mut chat = read_chat_from_path(&path).context("failed to read the chat history")?;
+
+    let len = chat.messages.len();
+    for &n in indices {
+        let Some(entry) = chat.messages.get_mut(n) else {
+            eyre::bail!("there is no message #{n} (the chat has {len} messages)");
+        };
+        entry.metadata.pinned = pinned;
+    }
+
+    write_chat_to_path(&chat, path).context("failed to save chat history")
+}
+
+#[inline]
+fn handle_persona(
+    personas: &Personas,
+    session: &Session,
+    command: PersonaCommand,
+    wait: bool,
+) -> eyre::Result<()> {
+    match command {
+        PersonaCommand::List => {
+            let chat = read_chat_from_path(session.chat_path())
+                .context("failed to read the chat history")?;
+
+            let mut stdout = io::stdout().lock();
+            for name in personas.list().context("failed to list personas")? {
+                let prompt = personas.get(&name)?;
+                let marker = if chat.metadata.system.as_deref() == Some(prompt.as_str()) {
+                    '*'
+                } else {
+                    ' '
+                };
+                writeln!(
+                    stdout,
+                    "{marker} {name}: {snippet}",
+                    snippet = search::snippet(&prompt, None)
+                )
+                .context("failed to write persona to the standard output")?;
+            }
+        }
+        PersonaCommand::Add { name, prompt } => personas.add(&name, &prompt)?,
+        PersonaCommand::Remove { name } => personas.remove(&name)?,
+        PersonaCommand::Use { name } => {
+            let prompt = personas.get(&name)?;
+
+            let _lock = session.lock(wait)?;
+            let path = session.chat_path();
+            let mut chat = read_chat_from_path(&path).context("failed to read the chat history")?;
+            chat.metadata.system = Some(prompt);
+            write_chat_to_path(&chat, path).context("failed to save chat history")?;
+        }
+    }
+
+    Ok(())
+}
+
+#[inline]
+fn handle_sessions(sessions: &Sessions, command: SessionsCommand, wait: bool) -> eyre::Result<()> {
+    match command {
+        SessionsCommand::List => {
+            let current = sessions
+                .current()
+                .context("failed to obtain the current session")?;
+            let mut names = sessions.list().context("failed to list sessions")?;
+            if !names.contains(&current) {
+                names.push(current.clone());
+                names.sort_unstable();
+            }
+
+            let mut stdout = io::stdout().lock();
+            for name in names {
+                let marker = if name == current { '*' } else { ' ' };
+                writeln!(stdout, "{marker} {name}")
+                    .context("failed to write session name to the standard output")?;
+            }
+        }
+        SessionsCommand::New { name } => {
+            sessions.create(&name)?;
+            sessions.switch(&name)?;
+        }
+        SessionsCommand::Rename { from, to } => sessions.rename(&from, &to, wait)?,
+        SessionsCommand::Delete { name } => sessions.delete(&name, wait)?,
+        SessionsCommand::Switch { name } => sessions.switch(&name)?,
+    }
+
+    Ok(())
+}
+
+/// What becomes of a message when the chat is sent.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ContextStatus {
+    /// Left out to fit the chat into the context window.
+    Dropped,
+    /// Sent, but truncated by the context strategy afterwards.
+    Truncated,
+    Sent,
+}
+
+/// Statuses of the messages of `chat`
+/// when fitting it into the context window drops `n_dropped` of them
+/// and the context strategy truncates `n_truncated` more.
+///
+/// Both drop the oldest unpinned messages first.
+#[inline]
+fn context_statuses(
+    chat: &[ChatEntry],
+    n_dropped: usize,
+    n_truncated: usize,
+) -> Vec<ContextStatus> {
+    let mut n_unpinned = 0;
+    chat.iter()
+        .map(|entry| {
+            if entry.metadata.pinned {
+                return ContextStatus::Sent;
+            }
+            n_unpinned += 1;
+            if n_unpinned <= n_dropped {
+                ContextStatus::Dropped
+            } else if n_unpinned <= n_dropped + n_truncated {
+                ContextStatus::Truncated
+            } else {
+                ContextStatus::Sent
+            }
+        })
+        .collect()
+}
+
+#[inline]
+fn handle_context(session: &Session, budget: &TokenBudget, strategy: Strategy) -> eyre::Result<()> {
+    let chat =
+        read_chat_from_path(session.chat_path()).context("failed to read the chat history")?;
+    let mut stdout = io::stdout().lock();
+    if chat.messages.is_empty() {
+        writeln!(stdout, "chat is empty").context("failed to write to the standard output")?;
+        return Ok(());
+    }
+
+    let preamble = preamble(&chat.metadata, None)?;
+    let preamble_tokens: usize = preamble.iter().map(|message| budget.count(message)).sum();
+    let (current_chat, outdated_chat) =
+        tokens::fit_chat_to_budget(chat.messages.clone(), &budget.without(&preamble))
+            .context("failed to fit chat into the context window")?;
+    let n_dropped = outdated_chat.map_or(0, |outdated_chat| outdated_chat.len());
+
+    let analysis = if strategy == Strategy::Similarity {
+        context::Similarity::analyze(&current_chat)?
+    } else {
+        None
+    };
+    let (_, outdated_chat) = strategy
+        .build(budget)
+        .split(current_chat)
+        .context("failed to split chat")?;
+    let n_truncated = outdated_chat.map_or(0, |outdated_chat| outdated_chat.len());
+
+    let statuses = context_statuses(&chat.messages, n_dropped, n_truncated);
+    let sent: Vec<_> = (0..chat.messages.len())
+        .filter(|&n| statuses[n] != ContextStatus::Dropped)
+        .collect();
+
+    writeln!(stdout, "strategy: {strategy}")
+        .context("failed to write strategy to the standard output")?;
+    if let Some(analysis) = &analysis {
+        // Replies are never kept without their questions,
+        // so a reply's score stands for its question.
+        let describe = |n: usize, similarity: f32| {
+            let scored = analysis.scored_message(n, similarity);
+            if scored == n {
+                format!("#{} ({similarity:.3})", sent[n])
+            } else {
+                format!("#{} ({similarity:.3} via reply #{})", sent[n], sent[scored])
+            }
+        };
+        writeln!(
+            stdout,
+            "most similar to the last exchange: {}",
+            describe(analysis.most_similar, analysis.highest_similarity)
+        )
+        .context("failed to write analysis to the standard output")?;
+        writeln!(
+            stdout,
+            "least similar to the last exchange: {}",
+            describe(analysis.least_similar, analysis.lowest_similarity)
+        )
+        .context("failed to write analysis to the standard output")?;
+        if let Some(cut) = analysis.cut() {
+            writeln!(
+                stdout,
+                "cut: before #{} (the least similar message comes before the most similar one)",
+                sent[cut]
+            )
         } else {
-            writeln!(stdout, "{name}:", name = message.role)
-                .context("failed to write role to the standard output")?;
+            writeln!(
+                stdout,
+                "cut: none (the most similar message does not come after the least similar one)"
+            )
+        }
+        .context("failed to write analysis to the standard output")?;
+    } else if strategy == Strategy::Similarity {
+        writeln!(
+            stdout,
+            "cut: none (too few messages or embeddings that can't be compared)"
+        )
+        .context("failed to write analysis to the standard output")?;
+    }
+    writeln!(stdout).context("failed to write a new line to the standard output")?;
+
+    writeln!(
+        stdout,
+        "{:>5}  {:<9}  {:>6}  {:>10}  {:<24}  message",
+        "#", "role", "tokens", "similarity", "status"
+    )
+    .context("failed to write header to the standard output")?;
+    let mut total = preamble_tokens;
+    let labels = [
+        chat.metadata.system.as_ref().map(|_| "system"),
+        chat.metadata.summary.as_ref().map(|_| "summary"),
+    ];
+    for (label, message) in labels.into_iter().flatten().zip(&preamble) {
+        writeln!(
+            stdout,
+            "{:>5}  {label:<9}  {tokens:>6}  {:>10}  {:<24}  {snippet}",
+            "",
+            "",
+            "sent",
+            tokens = budget.count(message),
+            snippet = search::snippet(&message.content, None)
+        )
+        .context("failed to write preamble to the standard output")?;
+    }
+    for (n, entry) in chat.messages.iter().enumerate() {
+        let tokens = budget.count(&entry.message);
+        let similarity = analysis
+            .as_ref()
+            .and_then(|analysis| {
+                let position = sent.iter().position(|&m| m == n)?;
+                analysis.similarities.get(position).copied().flatten()
+            })
+            .map_or_else(String::new, |similarity| format!("{similarity:.3}"));
+        let status = match statuses[n] {
+            ContextStatus::Dropped => "dropped (context window)",
+            ContextStatus::Truncated => "sent, then truncated",
+            ContextStatus::Sent if entry.metadata.pinned => "sent (pinned)",
+            ContextStatus::Sent => "sent",
+        };
+        if statuses[n] != ContextStatus::Dropped {
+            total += tokens;
         }
-        writeln!(stdout, "{}", message.content)
-            .context("failed to write content to the standard output")?;
-        writeln!(stdout).context("failed to write a new line to the standard output")?;
-        stdout
-            .flush()
-            .context("failed to flush the standard output")?;
+        writeln!(
+            stdout,
+            "{n:>5}  {role:<9}  {tokens:>6}  {similarity:>10}  {status:<24}  {snippet}",
+            role = entry.message.role.to_string(),
+            snippet = search::snippet(&entry.message.content, None)
+        )
+        .context("failed to write message to the standard output")?;
     }
+    writeln!(stdout).context("failed to write a new line to the standard output")?;
+    writeln!(
+        stdout,
+        "about {total} of {available} tokens used",
+        available = budget.available()
+    )
+    .context("failed to write token count to the standard output")?;
 
     Ok(())
 }
 
+#[inline]
+fn handle_config(
+    files: &ConfigFiles,
+    profile: Option<&str>,
+    command: ConfigCommand,
+) -> eyre::Result<()> {
+    let mut stdout = io::stdout().lock();
+    match command {
+        ConfigCommand::Show => {
+            let mut settings = files.load(profile)?;
+            // Keep keys off screens and logs.
+            if let Some(api_key) = &mut settings.api_key {
+                let shown = api_key.len().saturating_sub(4);
+                *api_key = format!("...{}", api_key.get(shown..).unwrap_or_default());
+            }
+            for path in files.paths().filter(|path| path.is_file()) {
+                writeln!(stdout, "# {}", path.display())
+                    .context("failed to write path to the standard output")?;
+            }
+            let settings = toml::to_string(&settings).context("failed to serialize settings")?;
+            write!(stdout, "{settings}")
+                .context("failed to write settings to the standard output")?;
+        }
+        ConfigCommand::Edit { project } => {
+            let path = if project {
+                files.project_or_default()?
+            } else {
+                files.user.clone()
+            };
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir)
+                    .with_context(|| format!("failed to create {}", dir.display()))?;
+            }
+            editor::edit_file(&path)?;
+            // Point out mistakes while they're fresh.
+            config::read_config_file(&path)?;
+        }
+        ConfigCommand::Path => {
+            for path in files.paths() {
+                writeln!(stdout, "{}", path.display())
+                    .context("failed to write path to the standard output")?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[inline]
+async fn handle_models(client: &Client) -> eyre::Result<()> {
+    let response = client
+        .models()
+        .list()
+        .await
+        .context("failed to obtain the list of models")?;
+    let mut ids: Vec<_> = response.data.into_iter().map(|model| model.id).collect();
+    ids.sort_unstable();
+
+    let mut stdout = io::stdout().lock();
+    for id in ids {
+        writeln!(stdout, "{id}").context("failed to write model to the standard output")?;
+    }
+
+    Ok(())
+}
+
+/// Search messages by meaning if an embedder is given,
+/// or by text (or as a regular expression if `regex`) otherwise.
+#[inline]
+async fn handle_search(
+    embedder: Option<&Embedder>,
+    sessions: &[Session],
+    query: &str,
+    regex: bool,
+    limit: usize,
+) -> eyre::Result<()> {
+    let messages = search::stored_messages(sessions)?;
+    let (found, pattern) = if let Some(embedder) = embedder {
+        let query_embedding = embedder
+            .embed(query)
+            .await
+            .context("failed to embed query")?;
+        (search::semantic_search(messages, &query_embedding)?, None)
+    } else {
+        let pattern = search::text_pattern(query, regex)?;
+        (search::text_search(messages, &pattern), Some(pattern))
+    };
+
+    let mut stdout = io::stdout().lock();
+    for stored in found.into_iter().take(limit) {
+        let timestamp = stored
+            .timestamp
+            .map_or_else(|| "-".into(), search::format_timestamp);
+        writeln!(
+            stdout,
+            "{session}  {timestamp}  {role}: {snippet}",
+            session = stored.session,
+            role = stored.message.role,
+            snippet = search::snippet(&stored.message.content, pattern.as_ref())
+        )
+        .context("failed to write search result to the standard output")?;
+    }
+
+    Ok(())
+}
+
+/// Send `message` along with the chat context,
+/// stream the answer to the standard output and store both in the chat.
 #[inline]
 async fn handle_chat(
-    model: Model,
-    temperature: f32,
-    api_key: impl Into<String>,
-    path: impl AsRef<Path>,
+    client: &Client,
+    embedder: &Embedder,
+    settings: &ChatSettings,
+    session: &Session,
+    message: &str,
+    wait: bool,
 ) -> eyre::Result<()> {
-    let message =
-        read_message_from_stdin().context("failed to read message from the standard input")?;
+    let &ChatSettings {
+        ref model,
+        temperature,
+        strategy,
+        summarize,
+        ref system,
+        ref default_system,
+        pin,
+        recall,
+        ref recall_from,
+        ..
+    } = settings;
+    let budget = settings.budget();
+
     eyre::ensure!(
         !message.trim().is_empty(),
         "cannot use all-whitespace string as chat message"
     );
 
+    // Held until the updated chat is written back,
+    // so that concurrent invocations don't drop each other's messages.
+    let _lock = session.lock(wait)?;
+    let path = session.chat_path();
     let mut chat = read_chat_from_path(&path).context("failed to read chat history")?;
+    if let Some(system) = system {
+        chat.metadata.system = (!system.is_empty()).then(|| system.clone());
+    } else if chat.metadata.created.is_none() && chat.metadata.system.is_none() {
+        // Prompts given to the session otherwise (e.g., by a persona) win.
+        chat.metadata.system = default_system.clone().filter(|system| !system.is_empty());
+    }
 
-    let client = Client::new().with_api_key(api_key);
-
-    let message = strip_trailing_newline(&message);
-    let message_embedding = embed(&client, message)
-        .await
-        .context("failed to embed message")?;
-    chat.push((
+    let message = strip_trailing_newline(message);
+    // Recalling needs the embedding of the message right away,
+    // otherwise it is computed while the answer streams in.
+    let message_embedding = if recall > 0 {
+        let message_embedding = embedder
+            .embed(message)
+            .await
+            .context("failed to embed message")?;
+        Some(message_embedding)
+    } else {
+        None
+    };
+    let recalled = if let Some(message_embedding) = &message_embedding {
+        let candidates = recall::candidates(session, recall_from)
+            .context("failed to gather messages to recall")?;
+        let recalled = recall::recall(candidates, message_embedding, recall)
+            .context("failed to recall messages")?;
+        if recalled.is_empty() {
+            None
+        } else {
+            Some(recall::recall_message(&recalled)?)
+        }
+    } else {
+        None
+    };
+    let mut message_entry = ChatEntry::new(
         ChatCompletionRequestMessageArgs::default()
             .content(message)
             .build()
             .context("failed to build chat message")?,
-        message_embedding,
-    ));
+        PackedEmbedding::default(),
+    );
+    message_entry.metadata.timestamp = Some(history::now()?);
+    message_entry.metadata.tokens = Some(budget.count(&message_entry.message));
+    message_entry.metadata.pinned = pin;
+    chat.messages.push(message_entry);
+
+    let summarizer = |summary: Option<String>, outdated_chat: Vec<ChatEntry>| async move {
+        summary::summarize(client, model.name(), summary.as_deref(), &outdated_chat)
+            .await
+            .context("failed to summarize outdated chat")
+    };
+    let mut outdated_messages = fit_chat(
+        &mut chat,
+        &budget,
+        recalled.as_ref(),
+        summarize.then_some(&summarizer),
+    )
+    .await?;
 
     let request = CreateChatCompletionRequestArgs::default()
         .model(model.name())
         .temperature(temperature)
         .messages(
-            chat.iter()
-                .cloned()
-                .map(|(message, _)| message)
+            preamble(&chat.metadata, recalled.as_ref())?
+                .into_iter()
+                .chain(chat.messages.iter().map(|entry| entry.message.clone()))
                 .collect::<Vec<_>>(),
         )
         .build()
         .context("failed to build the completion request")?;
 
-    let mut stream = client
-        .chat()
-        .create_stream(request)
-        .await
-        .context("failed to create the completion stream")?;
-
-    let buffer = process_chat_response(&mut stream)
-        .await
-        .context("failed to process chat response")?;
+    let response = async {
+        let mut stream = client
+            .chat()
+            .create_stream(request)
+            .await
+            .context("failed to create the completion stream")?;
+        process_chat_response(&mut stream)
+            .await
+            .context("failed to process chat response")
+    };
+    let message_embedding = async {
+        match message_embedding {
+            Some(message_embedding) => Ok(message_embedding),
+            None => embedder
+                .embed(message)
+                .await
+                .context("failed to embed message"),
+        }
+    };
+    // The answer is always streamed in full,
+    // even if embedding the message fails meanwhile.
+    let (buffer, message_embedding) = future::join(response, message_embedding).await;
+    let buffer = buffer?;
+    // Fitting the chat into the context window never drops the new message.
+    let message_entry = chat
+        .messages
+        .last_mut()
+        .ok_or_else(|| eyre::eyre!("the new message is missing from the chat"))?;
+    message_entry.embedding = embedding_or_warning(message_embedding);
 
     let buffer = strip_trailing_newline(&buffer);
-    let buffer_embedding = embed(&client, buffer)
+    let buffer_embedding = embedder
+        .embed(buffer)
         .await
-        .context("failed to embed response")?;
-    chat.push((
+        .context("failed to embed response");
+    let mut buffer_entry = ChatEntry::new(
         ChatCompletionRequestMessageArgs::default()
             .content(buffer)
             .role(Role::Assistant)
             .build()
             .context("failed to build chat message")?,
-        buffer_embedding,
-    ));
+        embedding_or_warning(buffer_embedding),
+    );
+    let timestamp = history::now()?;
+    buffer_entry.metadata.timestamp = Some(timestamp);
+    buffer_entry.metadata.model = Some(model.name().into());
+    buffer_entry.metadata.temperature = Some(temperature);
+    buffer_entry.metadata.tokens = Some(budget.count(&buffer_entry.message));
+    chat.messages.push(buffer_entry);
+
+    let (current_chat, outdated_chat) = split_answered_chat(strategy, &budget, chat.messages);
+    if let Some(outdated_chat) = outdated_chat {
+        // The answer has been shown already,
+        // so failing to summarize must not lose the exchange.
+        if summarize && !outdated_chat.is_empty() {
+            match summarizer(chat.metadata.summary.clone(), outdated_chat.clone()).await {
+                Ok(summary) => chat.metadata.summary = Some(summary),
+                Err(error) => eprintln!("warning: {error:#}"),
+            }
+        }
+        outdated_messages.extend(outdated_chat);
+    }
 
-    let (current_chat, _outdated_chat) = split_chat(chat).context("failed to split chat")?;
+    // Archive before overwriting the chat history, so that a failure in
+    // between duplicates messages instead of losing them.
+    if !outdated_messages.is_empty() {
+        archive::append_to_archive(&outdated_messages, session.archive_path())
+            .context("failed to archive outdated chat")?;
+    }
 
-    write_chat_to_path(&current_chat, path).context("failed to save chat history")?;
+    chat.messages = current_chat;
+    chat.metadata.created.get_or_insert(timestamp);
+    chat.metadata.updated = Some(timestamp);
+    write_chat_to_path(&chat, path).context("failed to save chat history")?;
 
     Ok(())
 }
 
 #[inline]
-fn read_chat_from_path(path: impl AsRef<Path>) -> eyre::Result<Vec<EmbeddedMessage>> {
-    let path = path.as_ref();
-
-    let chat = if path
-        .try_exists()
-        .context("failed to check if chat history file exists")?
-    {
-        let contents = fs::read_to_string(path)
-            .with_context(|| format!("failed to read from {}", path.display()))?;
-
-        // https://github.com/serde-rs/json/issues/160#issuecomment-253446892
-        serde_json::from_str(&contents)
-            .with_context(|| format!("failed to deserialize contents of {}", path.display()))?
-    } else {
-        Vec::new()
-    };
-    Ok(chat)
-}
-
-#[inline]
-fn write_chat_to_path(chat: &[EmbeddedMessage], path: impl AsRef<Path>) -> eyre::Result<()> {
-    let path = path.as_ref();
+async fn handle_repl(
+    client: &Client,
+    embedder: &Embedder,
+    settings: &mut ChatSettings,
+    session: &Session,
+    history_path: &Path,
+    wait: bool,
+) -> eyre::Result<()> {
+    let mut editor = DefaultEditor::new().context("failed to set up line editing")?;
+    if history_path.exists() {
+        if let Err(error) = editor.load_history(history_path) {
+            eprintln!("warning: ignoring input history: {error}");
+        }
+    }
+    writeln!(
+        io::stdout(),
+        "Chatting in session '{}' with {}. Type /help for help.",
+        session.name(),
+        settings.model.name()
+    )
+    .context("failed to write greeting to the standard output")?;
+
+    while let Some(input) = read_input(&mut editor)? {
+        if input.trim().is_empty() {
+            continue;
+        }
+        if let Err(error) = editor.add_history_entry(input.as_str()) {
+            eprintln!("warning: failed to add input to history: {error}");
+        }
 
-    let file = fs::File::create(path).context("failed to create chat history file")?;
-    serde_json::to_writer(file, chat)
-        .with_context(|| format!("failed to serialize contents to {}", path.display()))?;
+        let result = match repl::input_parser(&input) {
+            Ok(Input::Message(message)) => {
+                let result = handle_chat(client, embedder, settings, session, &message, wait).await;
+                // `--pin` is meant for a single message.
+                if result.is_ok() {
+                    settings.pin = false;
+                }
+                result
+            }
+            Ok(Input::Reset) => reset_chat(session, wait),
+            Ok(Input::Model(None)) => writeln!(io::stdout(), "{}", settings.model.name())
+                .context("failed to write model to the standard output"),
+            Ok(Input::Model(Some(model))) => model_parser(&model).map(|model| {
+                settings.model = model;
+            }),
+            Ok(Input::Save(path)) => save_chat(session, &path),
+            Ok(Input::Help) => writeln!(io::stdout(), "{}", repl::HELP)
+                .context("failed to write help to the standard output"),
+            Ok(Input::Exit) => break,
+            Err(error) => Err(error),
+        };
+        // A failed turn shouldn't end the whole conversation.
+        if let Err(error) = result {
+            eprintln!("error: {error:#}");
+        }
+    }
 
+    if let Err(error) = editor.save_history(history_path) {
+        eprintln!("warning: failed to save input history: {error}");
+    }
     Ok(())
 }
 
+/// Read a (possibly multi-line) input,
+/// or nothing at the end of the input.
+///
+/// Lines ending with `\` continue on the next one,
+/// and Ctrl-C discards what was typed so far.
 #[inline]
-fn split_chat(
-    mut chat: Vec<EmbeddedMessage>,
-) -> eyre::Result<(Vec<EmbeddedMessage>, Option<Vec<EmbeddedMessage>>)> {
-    if chat.len() < 4 {
-        return Ok((chat, None));
+fn read_input(editor: &mut DefaultEditor) -> eyre::Result<Option<String>> {
+    let mut input = String::new();
+    loop {
+        let prompt = if input.is_empty() {
+            repl::PROMPT
+        } else {
+            repl::CONTINUATION_PROMPT
+        };
+        match editor.readline(prompt) {
+            Ok(line) => match line.strip_suffix('\\') {
+                Some(line) => {
+                    input.push_str(line);
+                    input.push('\n');
+                }
+                None => {
+                    input.push_str(&line);
+                    return Ok(Some(input));
+                }
+            },
+            Err(ReadlineError::Interrupted) => input.clear(),
+            Err(ReadlineError::Eof) => return Ok(None),
+            Err(error) => return Err(error).context("failed to read input"),
+        }
     }
+}
 
-    let (mut n_most_similar, mut n_least_similar) = {
-        let mut iter = chat.iter().enumerate().rev();
-        let last_response = iter.next().unwrap();
-        let last_request = iter.next().unwrap();
-
-        let most_similar = iter
-            .map(|(n, (_, embedding))| {
-                (
-                    n,
-                    cosine_similarity(embedding, &last_request.1 .1)
-                        .max(cosine_similarity(embedding, &last_response.1 .1)),
-                )
-            })
-            .max_by(|(_, x), (_, y)| x.partial_cmp(y).unwrap());
-
-        let mut iter = chat.iter().enumerate().rev();
-        let last_response = iter.next().unwrap();
-        let last_request = iter.next().unwrap();
-
-        let least_similar = iter
-            .map(|(n, (_, embedding))| {
-                (
-                    n,
-                    cosine_similarity(embedding, &last_request.1 .1)
-                        .max(cosine_similarity(embedding, &last_response.1 .1)),
-                )
-            })
-            .min_by(|(_, x), (_, y)| x.partial_cmp(y).unwrap());
-
-        let (most_similar, least_similar) = (most_similar.unwrap(), least_similar.unwrap());
-        eyre::ensure!(
-            most_similar.1 >= least_similar.1,
-            "most similar is less similar than least similar"
-        );
-        (most_similar.0, least_similar.0)
-    };
-
-    if chat[n_most_similar].0.role == Role::Assistant {
-        n_most_similar -= 1;
-    }
-    if chat[n_least_similar].0.role == Role::Assistant {
-        n_least_similar -= 1;
-    }
-    if n_most_similar <= n_least_similar {
-        return Ok((chat, None));
+/// Start the chat over,
+/// archiving all messages but the pinned ones.
+///
+/// The system prompt is kept,
+/// but the summary of older messages is not.
+#[inline]
+fn reset_chat(session: &Session, wait: bool) -> eyre::Result<()> {
+    let _lock = session.lock(wait)?;
+    let path = session.chat_path();
+    let mut chat = read_chat_from_path(&path).context("failed to read the chat history")?;
+
+    let (pinned, outdated): (Vec<_>, Vec<_>) = chat
+        .messages
+        .into_iter()
+        .partition(|entry| entry.metadata.pinned);
+    if !outdated.is_empty() {
+        archive::append_to_archive(&outdated, session.archive_path())
+            .context("failed to archive the chat")?;
     }
 
-    let current_chat = chat.split_off(n_least_similar);
-    let outdated_chat = chat;
-
-    Ok((current_chat, Some(outdated_chat)))
+    chat.messages = pinned;
+    chat.metadata.summary = None;
+    write_chat_to_path(&chat, path).context("failed to save chat history")
 }
 
 #[inline]
-async fn embed(client: &Client, input: &str) -> eyre::Result<Embedding> {
-    let request = CreateEmbeddingRequestArgs::default()
-        .model("text-embedding-ada-002")
-        .input(input)
-        .build()
-        .context("failed to create embedding request")?;
-    let response = client
-        .embeddings()
-        .create(request)
-        .await
-        .context("failed to obtain embedding response")?;
-    let data = response.data.into_iter().next();
-    let embedding = data
-        .map(|data| data.embedding)
-        .ok_or_else(|| eyre::eyre!("failed to embed '{input}'"))?;
-    eyre::ensure!(
-        embedding.len() == EMBEDDING_LENGTH,
-        "embedding has incorrect length (expected {EMBEDDING_LENGTH}, got {})",
-        embedding.len()
-    );
-    Ok(embedding)
+fn save_chat(session: &Session, path: &Path) -> eyre::Result<()> {
+    let file =
+        fs::File::create(path).with_context(|| format!("failed to create {}", path.display()))?;
+    let mut writer = io::BufWriter::new(file);
+    write_chat(&mut writer, session, false)
+        .with_context(|| format!("failed to write the chat to {}", path.display()))?;
+    writer
+        .flush()
+        .with_context(|| format!("failed to write the chat to {}", path.display()))
 }
 
+/// Cosine similarity of two embeddings.
+///
+/// The similarity is undefined for zero vectors (and vectors with
+/// non-finite components),
+/// which are taken as unrelated to anything instead,
+/// i.e., the result is never NaN.
 // https://github.com/openai/openai-python/blob/47ce29542e7fc496c1cd0bb323293b7991f45bb0/openai/embeddings_utils.py#L67-L68
 #[inline]
 fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
@@ -606,7 +2328,12 @@ fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
     fn dot(a: &[f32], b: &[f32]) -> f32 {
         a.iter().zip(b).map(|(a, b)| a * b).sum()
     }
-    dot(a, b) / (dot(a, a) * dot(b, b)).sqrt()
+    let similarity = dot(a, b) / (dot(a, a) * dot(b, b)).sqrt();
+    if similarity.is_finite() {
+        similarity
+    } else {
+        0.0
+    }
 }
 
 // https://stackoverflow.com/a/66401342/4039050
@@ -620,8 +2347,21 @@ fn strip_trailing_newline(input: &str) -> &str {
 
 #[cfg(test)]
 mod tests {
+    use std::cell::RefCell;
+
     use super::*;
 
+    /// A chat of alternating user and assistant messages without embeddings.
+    fn chat(contents: &[&str]) -> Vec<ChatEntry> {
+        history::test_chat(contents.iter().map(|&content| (content, Vec::new())))
+    }
+
+    fn contents(chat: &[ChatEntry]) -> Vec<String> {
+        chat.iter()
+            .map(|entry| entry.message.content.clone())
+            .collect()
+    }
+
     #[test]
     fn verify_cli() {
         use clap::CommandFactory;
@@ -629,6 +2369,218 @@ mod tests {
         Cli::command().debug_assert();
     }
 
+    #[test]
+    fn parse_args_takes_free_text_for_a_message() {
+        let parse = |args: &[&str]| {
+            let args = ["cligpt"].iter().chain(args).map(OsString::from).collect();
+            parse_args(args).and_then(|matches| Cli::from_arg_matches(&matches))
+        };
+        let message = |cli: Cli| {
+            assert!(cli.command.is_none());
+            cli.message.join(" ")
+        };
+
+        assert_eq!(
+            message(parse(&["show", "me", "how"]).unwrap()),
+            "show me how"
+        );
+        assert_eq!(
+            message(parse(&["--model", "gpt4", "show", "the", "bug"]).unwrap()),
+            "show the bug"
+        );
+        assert_eq!(message(parse(&["--", "show"]).unwrap()), "show");
+        assert_eq!(
+            message(parse(&["explain", "show"]).unwrap()),
+            "explain show"
+        );
+
+        assert!(matches!(
+            parse(&["show"]).unwrap().command,
+            Some(Command::Show { .. })
+        ));
+        assert!(matches!(
+            parse(&["search", "bug"]).unwrap().command,
+            Some(Command::Search { .. })
+        ));
+        assert!(parse(&["show", "--bogus"]).is_err());
+        assert!(parse(&["show", "--all", "x"]).is_err());
+        assert!(parse(&["show", "me", "--all"]).is_err());
+        assert!(parse(&["search", "foo", "bar"]).is_err());
+        assert!(parse(&["--model", "gpt4", "search", "for", "the", "bug"]).is_err());
+        assert!(parse(&["context", "foo"]).is_err());
+        assert!(parse(&["models", "list"]).is_err());
+        assert!(parse(&["sessions", "new", "a", "b"]).is_err());
+    }
+
+    #[test]
+    fn chat_options_follow_commands() {
+        let matches = Cli::command()
+            .try_get_matches_from(["cligpt", "context", "--context-strategy", "none"])
+            .unwrap();
+        assert_eq!(
+            matches.value_source("context_strategy"),
+            Some(ValueSource::CommandLine)
+        );
+        let cli = Cli::from_arg_matches(&matches).unwrap();
+        assert!(matches!(cli.command, Some(Command::Context)));
+        assert_eq!(cli.context_strategy, Strategy::None);
+
+        let cli = Cli::parse_from(["cligpt", "models", "--api-base", "http://localhost:8080/v1"]);
+        assert!(matches!(cli.command, Some(Command::Models)));
+        assert_eq!(cli.api_base, "http://localhost:8080/v1");
+    }
+
+    #[test]
+    fn no_summarize_overrides_the_strategy() {
+        let summarizes =
+            |args: &[&str]| Cli::parse_from(["cligpt"].iter().chain(args)).summarizes();
+
+        assert!(!summarizes(&[]));
+        assert!(summarizes(&["--summarize"]));
+        assert!(summarizes(&["--context-strategy", "summarize"]));
+        assert!(!summarizes(&[
+            "--context-strategy",
+            "summarize",
+            "--no-summarize"
+        ]));
+        assert!(summarizes(&["--no-summarize", "--summarize"]));
+    }
+
+    #[tokio::test]
+    async fn fit_chat_summarizes_until_the_chat_fits() -> eyre::Result<()> {
+        let new_chat = || Chat {
+            messages: chat(&["one", "two", "three", "four", "five", "six"]),
+            ..Chat::default()
+        };
+        // Six messages of five tokens each don't fit,
+        // and neither do four along with the summary.
+        let budget = TokenBudget::for_model("gpt-4", Some(31), 0);
+
+        let calls = RefCell::new(Vec::new());
+        let summarizer = |summary: Option<String>, outdated_chat: Vec<ChatEntry>| {
+            calls.borrow_mut().push((summary, contents(&outdated_chat)));
+            future::ready(eyre::Ok("The user counted.".to_owned()))
+        };
+        let mut chat = new_chat();
+        let outdated_chat = fit_chat(&mut chat, &budget, None, Some(summarizer)).await?;
+        assert_eq!(contents(&outdated_chat), ["one", "two", "three", "four"]);
+        assert_eq!(contents(&chat.messages), ["five", "six"]);
+        assert_eq!(chat.metadata.summary.as_deref(), Some("The user counted."));
+        assert_eq!(
+            calls.into_inner(),
+            [
+                (None, vec!["one".to_owned(), "two".to_owned()]),
+                (
+                    Some("The user counted.".to_owned()),
+                    vec!["three".to_owned(), "four".to_owned()]
+                ),
+            ]
+        );
+
+        // Failing to summarize falls back to dropping messages.
+        let failing =
+            |_: Option<String>, _: Vec<ChatEntry>| future::ready(Err(eyre::eyre!("no summary")));
+        let mut chat = new_chat();
+        let outdated_chat = fit_chat(&mut chat, &budget, None, Some(failing)).await?;
+        assert_eq!(contents(&outdated_chat), ["one", "two"]);
+        assert_eq!(contents(&chat.messages), ["three", "four", "five", "six"]);
+        assert_eq!(chat.metadata.summary, None);
+        Ok(())
+    }
+
+    #[test]
+    fn split_answered_chat_keeps_an_answer_over_the_budget() {
+        // The question fits along with the earlier exchange,
+        // but the answer alone doesn't.
+        let budget = TokenBudget::for_model("gpt-4", Some(20), 0);
+        let chat = chat(&[
+            "one",
+            "two",
+            "three",
+            "four five six seven eight nine ten eleven twelve thirteen fourteen fifteen",
+        ]);
+        assert!(tokens::fit_chat_to_budget(chat.clone(), &budget).is_err());
+
+        for strategy in [Strategy::TokenBudget, Strategy::Summarize] {
+            let (current_chat, outdated_chat) =
+                split_answered_chat(strategy, &budget, chat.clone());
+            assert_eq!(contents(&current_chat), contents(&chat));
+            assert!(outdated_chat.is_none());
+        }
+
+        let (current_chat, outdated_chat) =
+            split_answered_chat(Strategy::SlidingWindow(2), &budget, chat);
+        assert_eq!(contents(&current_chat)[0], "three");
+        assert_eq!(contents(&outdated_chat.unwrap()), ["one", "two"]);
+    }
+
+    #[test]
+    fn context_statuses_match_fitting_and_truncating() -> eyre::Result<()> {
+        let mut chat = chat(&[
+            "one", "two", "three", "four", "five", "six", "seven", "eight",
+        ]);
+        chat[0].metadata.pinned = true;
+        chat[3].metadata.pinned = true;
+
+        // Eight messages of five tokens each don't fit.
+        let budget = TokenBudget::for_model("gpt-4", Some(33), 0);
+        let (current_chat, outdated_chat) = tokens::fit_chat_to_budget(chat.clone(), &budget)?;
+        let (sent_chat, truncated_chat) = Strategy::SlidingWindow(2)
+            .build(&budget)
+            .split(current_chat)?;
+        let (outdated_chat, truncated_chat) = (outdated_chat.unwrap(), truncated_chat.unwrap());
+
+        let statuses = context_statuses(&chat, outdated_chat.len(), truncated_chat.len());
+        let with_status = |status| {
+            chat.iter()
+                .zip(&statuses)
+                .filter(|&(_, &s)| s == status)
+                .map(|(entry, _)| entry.message.content.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(with_status(ContextStatus::Dropped), ["two", "three"]);
+        assert_eq!(
+            with_status(ContextStatus::Dropped),
+            contents(&outdated_chat)
+        );
+        assert_eq!(with_status(ContextStatus::Truncated), ["five", "six"]);
+        assert_eq!(
+            with_status(ContextStatus::Truncated),
+            contents(&truncated_chat)
+        );
+        assert_eq!(
+            with_status(ContextStatus::Sent),
+            ["one", "four", "seven", "eight"]
+        );
+        assert_eq!(with_status(ContextStatus::Sent), contents(&sent_chat));
+
+        assert_eq!(context_statuses(&chat, 0, 0), [ContextStatus::Sent; 8]);
+        Ok(())
+    }
+
+    #[test]
+    fn model_parser_works() {
+        assert_eq!(model_parser("gpt35").unwrap().name(), "gpt-3.5-turbo");
+        assert_eq!(model_parser("gpt4").unwrap().name(), "gpt-4");
+        assert_eq!(model_parser("gpt-4-32k").unwrap().name(), "gpt-4-32k");
+        assert_eq!(
+            model_parser("ft:gpt-3.5-turbo:acme::abc123").unwrap(),
+            Model::Other("ft:gpt-3.5-turbo:acme::abc123".into())
+        );
+        assert!(model_parser(" ").is_err());
+    }
+
+    #[test]
+    fn api_base_parser_works() {
+        assert_eq!(
+            api_base_parser("http://localhost:8080/v1/").unwrap(),
+            "http://localhost:8080/v1"
+        );
+        assert_eq!(api_base_parser(API_BASE).unwrap(), API_BASE);
+        assert!(api_base_parser("").is_err());
+        assert!(api_base_parser("localhost:8080").is_err());
+    }
+
     #[test]
     fn strip_newline_works() {
         assert_eq!(strip_trailing_newline("Test0\r\n\r\n"), "Test0\r\n");
@@ -644,5 +2596,8 @@ mod tests {
         assert_abs_diff_eq!(cosine_similarity(&[0.0, 1.0], &[0.0, 1.0]), 1.0);
         assert_abs_diff_eq!(cosine_similarity(&[0.0, 1.0], &[1.0, 0.0]), 0.0);
         assert_abs_diff_eq!(cosine_similarity(&[0.0, 1.0], &[0.5, 0.5]), 0.707_106_77);
+        assert_abs_diff_eq!(cosine_similarity(&[0.0, 0.0], &[0.0, 0.0]), 0.0);
+        assert_abs_diff_eq!(cosine_similarity(&[0.0, 1.0], &[f32::NAN, 1.0]), 0.0);
+        assert_abs_diff_eq!(cosine_similarity(&[0.0, 1.0], &[f32::INFINITY, 1.0]), 0.0);
     }
 }
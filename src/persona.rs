@@ -0,0 +1,186 @@
+//! System prompts and a library of reusable ones, called personas.
+//!
+//! Each persona is stored as a plain text file in the personas directory,
+//! so they can also be written with any editor.
+
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path::PathBuf;
+
+use async_openai::types::ChatCompletionRequestMessage;
+use async_openai::types::ChatCompletionRequestMessageArgs;
+use async_openai::types::Role;
+use color_eyre::eyre;
+use color_eyre::eyre::Context;
+
+use crate::history::write_atomically;
+
+const PERSONA_EXTENSION: &str = "txt";
+
+/// The system message carrying a system prompt to the model.
+#[inline]
+pub fn system_message(prompt: &str) -> eyre::Result<ChatCompletionRequestMessage> {
+    ChatCompletionRequestMessageArgs::default()
+        .role(Role::System)
+        .content(prompt)
+        .build()
+        .context("failed to build system message")
+}
+
+/// A library of personas stored in a directory.
+pub struct Personas {
+    dir: PathBuf,
+}
+
+impl Personas {
+    #[inline]
+    pub fn open(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// Names of all personas, sorted.
+    #[inline]
+    pub fn list(&self) -> eyre::Result<Vec<String>> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => {
+                return Err(err).with_context(|| format!("failed to read {}", self.dir.display()))
+            }
+        };
+
+        let mut names = Vec::new();
+        for entry in entries {
+            let path = entry
+                .with_context(|| format!("failed to read {}", self.dir.display()))?
+                .path();
+            if path.extension().is_some_and(|ext| ext == PERSONA_EXTENSION) {
+                if let Some(name) = path.file_stem().and_then(|stem| stem.to_str()) {
+                    names.push(name.to_owned());
+                }
+            }
+        }
+        names.sort_unstable();
+        Ok(names)
+    }
+
+    /// The system prompt of a persona.
+    #[inline]
+    pub fn get(&self, name: &str) -> eyre::Result<String> {
+        let path = self.path(name);
+        match fs::read_to_string(&path) {
+            Ok(prompt) => Ok(prompt.trim().into()),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                eyre::bail!("persona '{name}' does not exist")
+            }
+            Err(err) => Err(err).with_context(|| format!("failed to read {}", path.display())),
+        }
+    }
+
+    #[inline]
+    pub fn add(&self, name: &str, prompt: &str) -> eyre::Result<()> {
+        eyre::ensure!(
+            !prompt.trim().is_empty(),
+            "cannot use all-whitespace string as persona"
+        );
+        let path = self.path(name);
+        eyre::ensure!(!path.exists(), "persona '{name}' already exists");
+
+        fs::create_dir_all(&self.dir)
+            .with_context(|| format!("failed to create {}", self.dir.display()))?;
+        write_atomically(&path, |writer| {
+            writer
+                .write_all(prompt.as_bytes())
+                .with_context(|| format!("failed to write {}", path.display()))
+        })
+    }
+
+    #[inline]
+    pub fn remove(&self, name: &str) -> eyre::Result<()> {
+        let path = self.path(name);
+        match fs::remove_file(&path) {
+            Ok(()) => Ok(()),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                eyre::bail!("persona '{name}' does not exist")
+            }
+            Err(err) => Err(err).with_context(|| format!("failed to remove {}", path.display())),
+        }
+    }
+
+    #[inline]
+    fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(name).with_extension(PERSONA_EXTENSION)
+    }
+}
+
+#[inline]
+pub fn persona_name_parser(name: &str) -> eyre::Result<String> {
+    eyre::ensure!(!name.is_empty(), "cannot use empty string as persona name");
+    eyre::ensure!(
+        !name.starts_with('.'),
+        "'{name}' cannot be used as persona name (starts with '.')"
+    );
+    if let Some(offending_char) = name
+        .chars()
+        .find(|&c| !(c.is_alphanumeric() || matches!(c, '-' | '_')))
+    {
+        eyre::bail!("'{name}' contains invalid character '{offending_char}'");
+    }
+
+    Ok(name.into())
+}
+
+/// A system prompt given either as text,
+/// or as `@path` to read it from a file.
+#[inline]
+pub fn prompt_parser(prompt: &str) -> eyre::Result<String> {
+    let prompt = match prompt.strip_prefix('@') {
+        Some(path) => fs::read_to_string(path)
+            .with_context(|| format!("failed to read prompt from {path}"))?,
+        None => prompt.into(),
+    };
+    Ok(prompt.trim().into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn persona_lifecycle() -> eyre::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let personas = Personas::open(dir.path().join("personas"));
+        assert!(personas.list()?.is_empty());
+
+        personas.add("reviewer", "You review Rust code.")?;
+        personas.add("terse", "Answer in one sentence.")?;
+        assert!(personas.add("terse", "Again.").is_err());
+        assert!(personas.add("blank", "  ").is_err());
+        assert_eq!(personas.list()?, ["reviewer", "terse"]);
+        assert_eq!(personas.get("terse")?, "Answer in one sentence.");
+
+        personas.remove("terse")?;
+        assert!(personas.get("terse").is_err());
+        assert!(personas.remove("terse").is_err());
+        assert_eq!(personas.list()?, ["reviewer"]);
+        Ok(())
+    }
+
+    #[test]
+    fn prompt_parser_works() -> eyre::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("prompt.txt");
+        fs::write(&path, "You are a pirate.\n")?;
+
+        assert_eq!(prompt_parser(" Be brief. ")?, "Be brief.");
+        assert_eq!(
+            prompt_parser(&format!("@{}", path.display()))?,
+            "You are a pirate."
+        );
+        assert!(prompt_parser("@/nonexistent/prompt.txt").is_err());
+        assert!(persona_name_parser("code-reviewer_2").is_ok());
+        assert!(persona_name_parser("../escape").is_err());
+        Ok(())
+    }
+}
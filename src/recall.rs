@@ -0,0 +1,146 @@
+//! Retrieval of past messages relevant to a new one.
+
+use std::fmt::Write as _;
+
+use async_openai::types::ChatCompletionRequestMessage;
+use async_openai::types::ChatCompletionRequestMessageArgs;
+use async_openai::types::Role;
+use color_eyre::eyre;
+use color_eyre::eyre::Context;
+
+use crate::archive;
+use crate::cosine_similarity;
+use crate::embedding::PackedEmbedding;
+use crate::history::read_chat_from_path;
+use crate::session::Session;
+
+/// A past message together with where it came from and how similar it is
+/// to the new message.
+#[derive(Debug)]
+pub struct Recalled {
+    pub session: Option<String>,
+    pub message: ChatCompletionRequestMessage,
+    pub similarity: f32,
+}
+
+/// Candidate messages for recall,
+/// optionally labelled with the session they come from.
+pub type Candidates = Vec<(
+    Option<String>,
+    ChatCompletionRequestMessage,
+    PackedEmbedding,
+)>;
+
+/// Gather the archived messages of the current session,
+/// and every message of `others`.
+#[inline]
+pub fn candidates(session: &Session, others: &[Session]) -> eyre::Result<Candidates> {
+    let mut candidates: Candidates = archive::read_archive(session.archive_path())
+        .context("failed to read the chat archive")?
+        .into_iter()
+        .map(|archived| (None, archived.message, archived.embedding))
+        .collect();
+
+    for other in others {
+        let name = Some(other.name().to_owned());
+        let archived = archive::read_archive(other.archive_path())
+            .with_context(|| format!("failed to read the archive of session '{}'", other.name()))?
+            .into_iter()
+            .map(|archived| (archived.message, archived.embedding));
+        let current = read_chat_from_path(other.chat_path())
+            .with_context(|| format!("failed to read the chat of session '{}'", other.name()))?
+            .messages
+            .into_iter()
+            .map(|entry| (entry.message, entry.embedding));
+        candidates.extend(
+            archived
+                .chain(current)
+                .map(|(message, embedding)| (name.clone(), message, embedding)),
+        );
+    }
+
+    Ok(candidates)
+}
+
+/// The `k` candidates most similar to `query`,
+/// most similar first.
+#[inline]
+pub fn recall(candidates: Candidates, query: &[f32], k: usize) -> eyre::Result<Vec<Recalled>> {
+    let mut recalled = Vec::new();
+    for (session, message, embedding) in candidates {
+        let embedding = embedding.unpack()?;
+        if embedding.len() != query.len() {
+            continue;
+        }
+        let similarity = cosine_similarity(&embedding, query);
+        recalled.push(Recalled {
+            session,
+            message,
+            similarity,
+        });
+    }
+
+    recalled.sort_by(|a, b| b.similarity.total_cmp(&a.similarity));
+    recalled.truncate(k);
+    Ok(recalled)
+}
+
+/// The system message carrying recalled messages to the model.
+#[inline]
+pub fn recall_message(recalled: &[Recalled]) -> eyre::Result<ChatCompletionRequestMessage> {
+    let mut content = String::from("Possibly relevant messages from earlier conversations:\n");
+    for Recalled {
+        session, message, ..
+    } in recalled
+    {
+        match session {
+            Some(session) => writeln!(content, "\n{} (session '{session}'):", message.role),
+            None => writeln!(content, "\n{}:", message.role),
+        }
+        .context("failed to write recalled message role")?;
+        writeln!(content, "{}", message.content).context("failed to write recalled message")?;
+    }
+
+    ChatCompletionRequestMessageArgs::default()
+        .role(Role::System)
+        .content(content)
+        .build()
+        .context("failed to build recall message")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::test_entry;
+
+    fn candidate(
+        content: &str,
+        embedding: Vec<f32>,
+    ) -> eyre::Result<(
+        Option<String>,
+        ChatCompletionRequestMessage,
+        PackedEmbedding,
+    )> {
+        let entry = test_entry(Role::User, content, embedding);
+        Ok((None, entry.message, entry.embedding))
+    }
+
+    #[test]
+    fn recall_ranks_by_similarity() -> eyre::Result<()> {
+        let candidates = vec![
+            candidate("orthogonal", vec![0.0, 1.0])?,
+            candidate("identical", vec![1.0, 0.0])?,
+            candidate("close", vec![1.0, 0.5])?,
+            candidate("zero", vec![0.0, 0.0])?,
+            candidate("wrong length", vec![1.0, 0.0, 0.0])?,
+        ];
+
+        let recalled = recall(candidates, &[1.0, 0.0], 2)?;
+        let contents: Vec<_> = recalled
+            .iter()
+            .map(|recalled| recalled.message.content.as_str())
+            .collect();
+        assert_eq!(contents, ["identical", "close"]);
+        Ok(())
+    }
+}
@@ -0,0 +1,92 @@
+//! Parsing input of the interactive mode.
+//!
+//! Input starting with `/` is a command,
+//! anything else is a message to send
+//! (start it with `//` to send a message starting with `/`).
+
+use std::path::PathBuf;
+
+use color_eyre::eyre;
+
+/// Prompt shown before the first line of input.
+pub const PROMPT: &str = ">>> ";
+/// Prompt shown before lines continuing the input.
+pub const CONTINUATION_PROMPT: &str = "... ";
+
+/// Help shown by `/help`.
+pub const HELP: &str = "\
+Type a message and press Enter to send it.
+End a line with \\ to continue the message on the next one.
+
+/reset         archive the chat and start over (keeps the system prompt and pinned messages)
+/model [NAME]  show the model in use, or switch to another one
+/save PATH     save the chat to a file
+/help          show this help
+/exit          leave (as does Ctrl-D)";
+
+/// Something typed in the interactive mode.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Input {
+    Message(String),
+    Reset,
+    Model(Option<String>),
+    Save(PathBuf),
+    Help,
+    Exit,
+}
+
+#[inline]
+pub fn input_parser(input: &str) -> eyre::Result<Input> {
+    let Some(command) = input.strip_prefix('/') else {
+        return Ok(Input::Message(input.into()));
+    };
+    if command.starts_with('/') {
+        return Ok(Input::Message(command.into()));
+    }
+
+    let (name, argument) = match command.trim().split_once(char::is_whitespace) {
+        Some((name, argument)) => (name, Some(argument.trim())),
+        None => (command.trim(), None),
+    };
+    Ok(match (name, argument) {
+        ("reset", None) => Input::Reset,
+        ("model", argument) => Input::Model(argument.map(Into::into)),
+        ("save", Some(path)) => Input::Save(path.into()),
+        ("save", None) => eyre::bail!("/save requires a path"),
+        ("help", None) => Input::Help,
+        ("exit" | "quit", None) => Input::Exit,
+        ("reset" | "help" | "exit" | "quit", Some(_)) => {
+            eyre::bail!("/{name} takes no arguments")
+        }
+        _ => eyre::bail!("unknown command '/{name}' (type /help for a list of commands)"),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn input_parser_works() -> eyre::Result<()> {
+        assert_eq!(input_parser("hello")?, Input::Message("hello".into()));
+        assert_eq!(
+            input_parser("//usr/bin is?")?,
+            Input::Message("/usr/bin is?".into())
+        );
+        assert_eq!(input_parser("/reset")?, Input::Reset);
+        assert_eq!(input_parser("/model")?, Input::Model(None));
+        assert_eq!(
+            input_parser("/model  gpt-4 ")?,
+            Input::Model(Some("gpt-4".into()))
+        );
+        assert_eq!(
+            input_parser("/save chat.txt")?,
+            Input::Save("chat.txt".into())
+        );
+        assert_eq!(input_parser("/quit")?, Input::Exit);
+        assert!(input_parser("/save").is_err());
+        assert!(input_parser("/reset now").is_err());
+        assert!(input_parser("/unknown").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,245 @@
+//! Search over the stored messages of chat sessions.
+
+use async_openai::types::ChatCompletionRequestMessage;
+use color_eyre::eyre;
+use color_eyre::eyre::Context;
+use regex::Regex;
+use regex::RegexBuilder;
+
+use crate::archive;
+use crate::cosine_similarity;
+use crate::embedding::PackedEmbedding;
+use crate::history::read_chat_from_path;
+use crate::session::Session;
+
+/// Number of characters shown for each search result.
+const SNIPPET_LENGTH: usize = 80;
+/// Number of characters shown before a match.
+const SNIPPET_CONTEXT: usize = 20;
+
+/// A message stored in a session,
+/// either in its chat or in its archive.
+#[derive(Debug)]
+pub struct StoredMessage {
+    pub session: String,
+    pub timestamp: Option<u64>,
+    pub message: ChatCompletionRequestMessage,
+    pub embedding: PackedEmbedding,
+}
+
+/// Every message stored in `sessions`,
+/// archived messages of each session before those still in its chat.
+#[inline]
+pub fn stored_messages(sessions: &[Session]) -> eyre::Result<Vec<StoredMessage>> {
+    let mut messages = Vec::new();
+    for session in sessions {
+        let archived = archive::read_archive(session.archive_path())
+            .with_context(|| format!("failed to read the archive of session '{}'", session.name()))?
+            .into_iter()
+            .map(|archived| {
+                // Messages sent before timestamps were recorded are dated by
+                // when they were archived instead.
+                (
+                    archived.metadata.timestamp.or(Some(archived.timestamp)),
+                    archived.message,
+                    archived.embedding,
+                )
+            });
+        let current = read_chat_from_path(session.chat_path())
+            .with_context(|| format!("failed to read the chat of session '{}'", session.name()))?
+            .messages
+            .into_iter()
+            .map(|entry| (entry.metadata.timestamp, entry.message, entry.embedding));
+        messages.extend(
+            archived
+                .chain(current)
+                .map(|(timestamp, message, embedding)| StoredMessage {
+                    session: session.name().into(),
+                    timestamp,
+                    message,
+                    embedding,
+                }),
+        );
+    }
+    Ok(messages)
+}
+
+/// Case-insensitive pattern matching `query` literally,
+/// or as a regular expression if `regex`.
+#[inline]
+pub fn text_pattern(query: &str, regex: bool) -> eyre::Result<Regex> {
+    let pattern = if regex {
+        query.to_owned()
+    } else {
+        regex::escape(query)
+    };
+    RegexBuilder::new(&pattern)
+        .case_insensitive(true)
+        .build()
+        .with_context(|| format!("invalid regular expression '{query}'"))
+}
+
+/// Messages matching `pattern`,
+/// most recent first.
+#[inline]
+pub fn text_search(messages: Vec<StoredMessage>, pattern: &Regex) -> Vec<StoredMessage> {
+    let mut found: Vec<_> = messages
+        .into_iter()
+        .filter(|stored| pattern.is_match(&stored.message.content))
+        .collect();
+    // Messages without a timestamp predate timestamps altogether.
+    found.sort_by_key(|stored| std::cmp::Reverse(stored.timestamp));
+    found
+}
+
+/// Messages ordered by their similarity to `query`,
+/// most similar first.
+///
+/// Messages whose embeddings can't be compared to `query` are left out.
+#[inline]
+pub fn semantic_search(
+    messages: Vec<StoredMessage>,
+    query: &[f32],
+) -> eyre::Result<Vec<StoredMessage>> {
+    let mut found = Vec::new();
+    for stored in messages {
+        let embedding = stored.embedding.unpack()?;
+        if embedding.len() != query.len() {
+            continue;
+        }
+        let similarity = cosine_similarity(&embedding, query);
+        found.push((similarity, stored));
+    }
+
+    found.sort_by(|(a, _), (b, _)| b.total_cmp(a));
+    Ok(found.into_iter().map(|(_, stored)| stored).collect())
+}
+
+/// A single line excerpt of `content`,
+/// around the first match of `pattern` if given.
+#[inline]
+pub fn snippet(content: &str, pattern: Option<&Regex>) -> String {
+    let content = content.split_whitespace().collect::<Vec<_>>().join(" ");
+    let start = pattern
+        .and_then(|pattern| pattern.find(&content))
+        .map_or(0, |found| {
+            content[..found.start()]
+                .chars()
+                .count()
+                .saturating_sub(SNIPPET_CONTEXT)
+        });
+
+    let mut snippet: String = content.chars().skip(start).take(SNIPPET_LENGTH).collect();
+    if start > 0 {
+        snippet.insert(0, '…');
+    }
+    if content.chars().count() > start + SNIPPET_LENGTH {
+        snippet.push('…');
+    }
+    snippet
+}
+
+/// Format seconds since the Unix epoch as a UTC date and time.
+#[inline]
+pub fn format_timestamp(timestamp: u64) -> String {
+    let (days, seconds) = (timestamp / 86_400, timestamp % 86_400);
+
+    // https://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02}",
+        seconds / 3600,
+        seconds % 3600 / 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use async_openai::types::Role;
+
+    use super::*;
+    use crate::history::test_entry;
+
+    fn stored(content: &str, timestamp: Option<u64>, embedding: Vec<f32>) -> StoredMessage {
+        let entry = test_entry(Role::User, content, embedding);
+        StoredMessage {
+            session: "default".into(),
+            timestamp,
+            message: entry.message,
+            embedding: entry.embedding,
+        }
+    }
+
+    fn contents(found: &[StoredMessage]) -> Vec<&str> {
+        found
+            .iter()
+            .map(|stored| stored.message.content.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn search_finds_messages() -> eyre::Result<()> {
+        let messages = || {
+            vec![
+                stored("old cat", None, vec![1.0, 0.0]),
+                stored("a dog", Some(2), vec![0.0, 1.0]),
+                stored("new cat", Some(3), vec![1.0, 0.5]),
+                stored("broken", Some(4), vec![0.0, 0.0]),
+            ]
+        };
+
+        let pattern = Regex::new("cat")?;
+        assert_eq!(
+            contents(&text_search(messages(), &pattern)),
+            ["new cat", "old cat"]
+        );
+        assert_eq!(
+            contents(&semantic_search(messages(), &[1.0, 0.0])?),
+            ["old cat", "new cat", "a dog", "broken"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn text_pattern_works() -> eyre::Result<()> {
+        assert!(text_pattern("foo(", false)?.is_match("call FOO(bar)"));
+        assert!(!text_pattern("a.c", false)?.is_match("abc"));
+        assert!(text_pattern("a.c", true)?.is_match("ABC"));
+        assert!(text_pattern("foo(", true).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn snippet_shows_match() -> eyre::Result<()> {
+        assert_eq!(snippet("short\nmessage", None), "short message");
+
+        let content = format!("{} needle {}", "a".repeat(50), "b".repeat(100));
+        let pattern = Regex::new("needle")?;
+        let found = snippet(&content, Some(&pattern));
+        assert!(found.starts_with('…'));
+        assert!(found.ends_with('…'));
+        assert!(found.contains("needle"));
+        Ok(())
+    }
+
+    #[test]
+    fn format_timestamp_works() {
+        assert_eq!(format_timestamp(0), "1970-01-01 00:00");
+        assert_eq!(format_timestamp(951_827_696), "2000-02-29 12:34");
+        assert_eq!(format_timestamp(1_790_000_000), "2026-09-21 14:13");
+    }
+}
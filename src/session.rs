@@ -0,0 +1,445 @@
+//! Named chat sessions.
+//!
+//! Every session lives in its own directory below a common root,
+//! and a small pointer file remembers which session is the current one.
+
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+
+use color_eyre::eyre;
+use color_eyre::eyre::Context;
+use fs4::FileExt;
+use fs4::TryLockError;
+
+use crate::history::write_atomically;
+
+pub const DEFAULT_SESSION: &str = "default";
+
+const CURRENT_FILE: &str = "current";
+const CHAT_FILE: &str = "chat.json";
+const ARCHIVE_FILE: &str = "archive.jsonl";
+const LOCK_FILE: &str = "lock";
+
+/// The collection of all stored chat sessions.
+#[derive(Debug)]
+pub struct Sessions {
+    root: PathBuf,
+}
+
+/// A single chat session.
+#[derive(Debug)]
+pub struct Session {
+    name: String,
+    dir: PathBuf,
+}
+
+/// An exclusive advisory lock on a session,
+/// released when dropped.
+#[derive(Debug)]
+pub struct SessionLock {
+    _file: fs::File,
+}
+
+impl Sessions {
+    #[inline]
+    pub fn open(root: impl Into<PathBuf>) -> eyre::Result<Self> {
+        let root = root.into();
+        fs::create_dir_all(root.join("sessions"))
+            .context("failed to create the sessions directory")?;
+        Ok(Self { root })
+    }
+
+    /// Move a single-session chat history from before sessions existed into
+    /// the default session.
+    #[inline]
+    pub fn migrate_legacy(&self, legacy_path: impl AsRef<Path>) -> eyre::Result<()> {
+        let legacy_path = legacy_path.as_ref();
+        if !legacy_path
+            .try_exists()
+            .context("failed to check if legacy chat history exists")?
+        {
+            return Ok(());
+        }
+
+        let session = self.session(DEFAULT_SESSION)?;
+        if session.exists()? {
+            return Ok(());
+        }
+        session.create()?;
+        move_file(legacy_path, &session.chat_path(), |from, to| {
+            fs::rename(from, to)
+        })
+        .with_context(|| {
+            format!(
+                "failed to move {} into the default session",
+                legacy_path.display()
+            )
+        })
+    }
+
+    #[inline]
+    pub fn session(&self, name: &str) -> eyre::Result<Session> {
+        let name = session_name_parser(name)?;
+        let dir = self.root.join("sessions").join(&name);
+        Ok(Session { name, dir })
+    }
+
+    /// Name of the session to use when none is given explicitly.
+    #[inline]
+    pub fn current(&self) -> eyre::Result<String> {
+        let path = self.root.join(CURRENT_FILE);
+        match fs::read_to_string(&path) {
+            Ok(name) => session_name_parser(name.trim())
+                .with_context(|| format!("invalid session name in {}", path.display())),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(DEFAULT_SESSION.into()),
+            Err(err) => Err(err).with_context(|| format!("failed to read from {}", path.display())),
+        }
+    }
+
+    #[inline]
+    pub fn switch(&self, name: &str) -> eyre::Result<()> {
+        let session = self.session(name)?;
+        eyre::ensure!(
+            session.name == DEFAULT_SESSION || session.exists()?,
+            "session '{name}' does not exist"
+        );
+
+        let path = self.root.join(CURRENT_FILE);
+        write_atomically(&path, |writer| {
+            writer
+                .write_all(session.name.as_bytes())
+                .with_context(|| format!("failed to write to {}", path.display()))
+        })?;
+        Ok(())
+    }
+
+    #[inline]
+    pub fn list(&self) -> eyre::Result<Vec<String>> {
+        let dir = self.root.join("sessions");
+        let mut names = Vec::new();
+        for entry in
+            fs::read_dir(&dir).with_context(|| format!("failed to read {}", dir.display()))?
+        {
+            let entry = entry.context("failed to read session directory entry")?;
+            if !entry
+                .file_type()
+                .context("failed to obtain session file type")?
+                .is_dir()
+            {
+                continue;
+            }
+            if let Some(name) = entry.file_name().to_str() {
+                if session_name_parser(name).is_ok() {
+                    names.push(name.to_owned());
+                }
+            }
+        }
+        names.sort_unstable();
+        Ok(names)
+    }
+
+    #[inline]
+    pub fn create(&self, name: &str) -> eyre::Result<Session> {
+        let session = self.session(name)?;
+        eyre::ensure!(!session.exists()?, "session '{name}' already exists");
+        session.create()?;
+        Ok(session)
+    }
+
+    /// Rename a session once no other process is using it.
+    #[inline]
+    pub fn rename(&self, from: &str, to: &str, wait: bool) -> eyre::Result<()> {
+        let source = self.session(from)?;
+        let target = self.session(to)?;
+        eyre::ensure!(source.exists()?, "session '{from}' does not exist");
+        eyre::ensure!(!target.exists()?, "session '{to}' already exists");
+
+        let _lock = source.lock(wait)?;
+        fs::rename(&source.dir, &target.dir)
+            .with_context(|| format!("failed to rename session '{from}' to '{to}'"))?;
+        if self.current()? == source.name {
+            self.switch(&target.name)?;
+        }
+        Ok(())
+    }
+
+    /// Delete a session once no other process is using it.
+    #[inline]
+    pub fn delete(&self, name: &str, wait: bool) -> eyre::Result<()> {
+        let session = self.session(name)?;
+        eyre::ensure!(session.exists()?, "session '{name}' does not exist");
+
+        let _lock = session.lock(wait)?;
+        fs::remove_dir_all(&session.dir)
+            .with_context(|| format!("failed to delete session '{name}'"))?;
+        if self.current()? == session.name {
+            self.switch(DEFAULT_SESSION)?;
+        }
+        Ok(())
+    }
+}
+
+impl Session {
+    #[inline]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    #[inline]
+    pub fn chat_path(&self) -> PathBuf {
+        self.dir.join(CHAT_FILE)
+    }
+
+    #[inline]
+    pub fn archive_path(&self) -> PathBuf {
+        self.dir.join(ARCHIVE_FILE)
+    }
+
+    #[inline]
+    pub fn exists(&self) -> eyre::Result<bool> {
+        self.dir
+            .try_exists()
+            .with_context(|| format!("failed to check if session '{}' exists", self.name))
+    }
+
+    /// Lock the session against other `cligpt` processes,
+    /// optionally waiting for them to finish.
+    #[inline]
+    pub fn lock(&self, wait: bool) -> eyre::Result<SessionLock> {
+        self.create()?;
+        let path = self.dir.join(LOCK_FILE);
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+            .with_context(|| format!("failed to open {}", path.display()))?;
+
+        match FileExt::try_lock(&file) {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) if wait => {
+                eprintln!(
+                    "waiting for another cligpt process to release session '{}'...",
+                    self.name
+                );
+                FileExt::lock(&file)
+                    .with_context(|| format!("failed to lock session '{}'", self.name))?;
+            }
+            Err(TryLockError::WouldBlock) => eyre::bail!(
+                "session '{}' is in use by another cligpt process (pass --wait to wait for it)",
+                self.name
+            ),
+            Err(TryLockError::Error(err)) => {
+                return Err(err).with_context(|| format!("failed to lock session '{}'", self.name));
+            }
+        }
+        self.ensure_locked(&file)?;
+
+        Ok(SessionLock { _file: file })
+    }
+
+    /// Make sure the locked `file` still belongs to the session,
+    /// which whoever held the lock before may have renamed or deleted.
+    #[inline]
+    fn ensure_locked(&self, file: &fs::File) -> eyre::Result<()> {
+        let path = self.dir.join(LOCK_FILE);
+        let locked = match fs::metadata(&path) {
+            Ok(metadata) => {
+                let file = file
+                    .metadata()
+                    .with_context(|| format!("failed to read metadata of {}", path.display()))?;
+                same_file(&file, &metadata)
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => false,
+            Err(err) => {
+                return Err(err)
+                    .with_context(|| format!("failed to read metadata of {}", path.display()));
+            }
+        };
+        eyre::ensure!(
+            locked,
+            "session '{}' was renamed or deleted while waiting",
+            self.name
+        );
+        Ok(())
+    }
+
+    /// Create the session directory if it doesn't exist yet.
+    #[inline]
+    pub fn create(&self) -> eyre::Result<()> {
+        fs::create_dir_all(&self.dir)
+            .with_context(|| format!("failed to create session '{}'", self.name))
+    }
+}
+
+#[inline]
+pub fn session_name_parser(name: &str) -> eyre::Result<String> {
+    eyre::ensure!(!name.is_empty(), "cannot use empty string as session name");
+    eyre::ensure!(
+        !name.starts_with('.'),
+        "'{name}' cannot be used as session name (starts with '.')"
+    );
+    if let Some(offending_char) = name
+        .chars()
+        .find(|&c| !(c.is_alphanumeric() || matches!(c, '-' | '_' | '.')))
+    {
+        eyre::bail!("'{name}' contains invalid character '{offending_char}'");
+    }
+
+    Ok(name.into())
+}
+
+#[cfg(unix)]
+#[inline]
+fn same_file(a: &fs::Metadata, b: &fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt as _;
+
+    (a.dev(), a.ino()) == (b.dev(), b.ino())
+}
+
+// Without file identities, a session that still has a lock file is taken to
+// be the same.
+#[cfg(not(unix))]
+#[inline]
+fn same_file(_a: &fs::Metadata, _b: &fs::Metadata) -> bool {
+    true
+}
+
+/// Move a file using `rename`,
+/// falling back to copying it when `rename` can't move it across filesystems
+/// (e.g., from a cache directory on tmpfs).
+#[inline]
+fn move_file(
+    from: &Path,
+    to: &Path,
+    rename: impl FnOnce(&Path, &Path) -> io::Result<()>,
+) -> eyre::Result<()> {
+    match rename(from, to) {
+        Ok(()) => return Ok(()),
+        Err(err) if err.kind() == io::ErrorKind::CrossesDevices => {}
+        Err(err) => {
+            return Err(err).with_context(|| {
+                format!("failed to rename {} to {}", from.display(), to.display())
+            })
+        }
+    }
+
+    let mut source =
+        fs::File::open(from).with_context(|| format!("failed to open {}", from.display()))?;
+    write_atomically(to, |writer| {
+        io::copy(&mut source, writer)
+            .with_context(|| format!("failed to copy {} to {}", from.display(), to.display()))?;
+        Ok(())
+    })?;
+    fs::remove_file(from).with_context(|| format!("failed to remove {}", from.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn session_name_parser_works() {
+        assert!(session_name_parser("default").is_ok());
+        assert!(session_name_parser("debug-2.0_notes").is_ok());
+        assert!(session_name_parser("").is_err());
+        assert!(session_name_parser(".hidden").is_err());
+        assert!(session_name_parser("../escape").is_err());
+        assert!(session_name_parser("with space").is_err());
+    }
+
+    #[test]
+    fn sessions_lifecycle_works() -> eyre::Result<()> {
+        let root = tempfile::tempdir()?;
+        let sessions = Sessions::open(root.path())?;
+        assert_eq!(sessions.current()?, DEFAULT_SESSION);
+        assert!(sessions.list()?.is_empty());
+
+        sessions.create("writing")?;
+        assert!(sessions.create("writing").is_err());
+        sessions.switch("writing")?;
+        assert_eq!(sessions.current()?, "writing");
+
+        let lock = sessions.session("writing")?.lock(false)?;
+        assert!(sessions.rename("writing", "essays", false).is_err());
+        drop(lock);
+        sessions.rename("writing", "essays", false)?;
+        assert_eq!(sessions.current()?, "essays");
+        assert_eq!(sessions.list()?, ["essays"]);
+
+        let lock = sessions.session("essays")?.lock(false)?;
+        assert!(sessions.delete("essays", false).is_err());
+        drop(lock);
+        sessions.delete("essays", false)?;
+        assert_eq!(sessions.current()?, DEFAULT_SESSION);
+        assert!(sessions.switch("essays").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn session_lock_notices_renames_and_deletions() -> eyre::Result<()> {
+        let root = tempfile::tempdir()?;
+        let sessions = Sessions::open(root.path())?;
+        sessions.create("drafts")?;
+        let session = sessions.session("drafts")?;
+
+        // As if another process renamed the session while this one waited.
+        let lock = session.lock(false)?;
+        fs::rename(&session.dir, session.dir.with_file_name("essays"))?;
+        let error = session.ensure_locked(&lock._file).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "session 'drafts' was renamed or deleted while waiting"
+        );
+
+        // Or deleted it and someone started a new one by the same name.
+        session.create()?;
+        assert!(session.ensure_locked(&lock._file).is_err());
+        drop(lock);
+
+        let lock = session.lock(false)?;
+        session.ensure_locked(&lock._file)?;
+        fs::remove_dir_all(&session.dir)?;
+        assert!(session.ensure_locked(&lock._file).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn session_lock_is_exclusive() -> eyre::Result<()> {
+        let root = tempfile::tempdir()?;
+        let sessions = Sessions::open(root.path())?;
+        let session = sessions.session(DEFAULT_SESSION)?;
+
+        let lock = session.lock(false)?;
+        assert!(session.lock(false).is_err());
+        drop(lock);
+        assert!(session.lock(false).is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn move_file_falls_back_to_copying() -> eyre::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let from = dir.path().join("chat.json");
+        let to = dir.path().join("moved.json");
+        fs::write(&from, "{}")?;
+
+        move_file(&from, &to, |_, _| {
+            Err(io::Error::from(io::ErrorKind::CrossesDevices))
+        })?;
+        assert!(!from.exists());
+        assert_eq!(fs::read_to_string(&to)?, "{}");
+
+        fs::write(&from, "[]")?;
+        assert!(move_file(&from, &to, |_, _| {
+            Err(io::Error::from(io::ErrorKind::PermissionDenied))
+        })
+        .is_err());
+        assert!(from.exists());
+        assert_eq!(fs::read_to_string(&to)?, "{}");
+        Ok(())
+    }
+}
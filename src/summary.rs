@@ -0,0 +1,111 @@
+//! Rolling summaries of messages truncated from the chat context.
+
+use std::fmt::Write as _;
+
+use async_openai::types::ChatCompletionRequestMessage;
+use async_openai::types::ChatCompletionRequestMessageArgs;
+use async_openai::types::CreateChatCompletionRequestArgs;
+use async_openai::types::Role;
+use async_openai::Client;
+use color_eyre::eyre;
+use color_eyre::eyre::Context;
+
+use crate::history::ChatEntry;
+
+const INSTRUCTIONS: &str = "You maintain a running summary of a conversation between a user and \
+                            an assistant. Update the summary with the new messages, keeping every \
+                            fact, decision and open question that may matter later. Be concise \
+                            and reply with the updated summary only.";
+
+/// The system message carrying a summary to the model.
+#[inline]
+pub fn summary_message(summary: &str) -> eyre::Result<ChatCompletionRequestMessage> {
+    ChatCompletionRequestMessageArgs::default()
+        .role(Role::System)
+        .content(format!("Summary of the conversation so far:\n{summary}"))
+        .build()
+        .context("failed to build summary message")
+}
+
+/// The request to fold `chat` into `summary`.
+#[inline]
+fn summary_prompt(summary: Option<&str>, chat: &[ChatEntry]) -> eyre::Result<String> {
+    let mut prompt = String::new();
+    if let Some(summary) = summary {
+        writeln!(prompt, "Current summary:\n{summary}\n")
+            .context("failed to write summary to prompt")?;
+    }
+    writeln!(prompt, "New messages:").context("failed to write to prompt")?;
+    for entry in chat {
+        writeln!(
+            prompt,
+            "\n{role}:\n{content}",
+            role = entry.message.role,
+            content = entry.message.content
+        )
+        .context("failed to write message to prompt")?;
+    }
+    Ok(prompt)
+}
+
+/// Fold messages into a summary using a model call.
+#[inline]
+pub async fn summarize(
+    client: &Client,
+    model: &str,
+    summary: Option<&str>,
+    chat: &[ChatEntry],
+) -> eyre::Result<String> {
+    let request = CreateChatCompletionRequestArgs::default()
+        .model(model)
+        .temperature(0.0)
+        .messages([
+            ChatCompletionRequestMessageArgs::default()
+                .role(Role::System)
+                .content(INSTRUCTIONS)
+                .build()
+                .context("failed to build instructions message")?,
+            ChatCompletionRequestMessageArgs::default()
+                .content(summary_prompt(summary, chat)?)
+                .build()
+                .context("failed to build prompt message")?,
+        ])
+        .build()
+        .context("failed to build the summary request")?;
+    let response = client
+        .chat()
+        .create(request)
+        .await
+        .context("failed to obtain summary response")?;
+
+    let summary = response
+        .choices
+        .into_iter()
+        .next()
+        .map(|choice| choice.message.content)
+        .ok_or_else(|| eyre::eyre!("summary response has no choices"))?;
+    let summary = summary.trim();
+    eyre::ensure!(!summary.is_empty(), "summary response is empty");
+    Ok(summary.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::test_chat;
+
+    #[test]
+    fn summary_prompt_works() -> eyre::Result<()> {
+        let chat = test_chat([("What is Rust?", vec![1.0]), ("A language.", vec![1.0])]);
+        assert_eq!(
+            summary_prompt(None, &chat)?,
+            "New messages:\n\nuser:\nWhat is Rust?\n\nassistant:\nA language.\n"
+        );
+        assert_eq!(
+            summary_prompt(Some("The user is learning."), &chat[..1])?,
+            "Current summary:\nThe user is learning.\n\nNew messages:\n\nuser:\nWhat is \
+             Rust?\n"
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,228 @@
+//! Token counting and context window budgets.
+
+use async_openai::types::ChatCompletionRequestMessage;
+use async_openai::types::Role;
+use color_eyre::eyre;
+use tiktoken_rs::CoreBPE;
+
+use crate::history::ChatEntry;
+
+/// Context window assumed for models whose window size is unknown.
+pub const DEFAULT_CONTEXT_WINDOW: usize = 4096;
+
+// https://github.com/openai/openai-cookbook/blob/main/examples/How_to_count_tokens_with_tiktoken.ipynb
+const TOKENS_PER_MESSAGE: usize = 3;
+const TOKENS_PER_NAME: usize = 1;
+const TOKENS_PER_REPLY: usize = 3;
+
+/// How many tokens a request to a given model may use.
+#[derive(Clone, Copy)]
+pub struct TokenBudget {
+    bpe: &'static CoreBPE,
+    context_window: usize,
+    reserved_tokens: usize,
+}
+
+impl TokenBudget {
+    /// Budget for a model,
+    /// keeping `reserved_tokens` free for the completion.
+    ///
+    /// Models unknown to the tokenizer are assumed to behave like `gpt-4`
+    /// and have a context window of either `context_window` or
+    /// [`DEFAULT_CONTEXT_WINDOW`] tokens.
+    #[inline]
+    pub fn for_model(model: &str, context_window: Option<usize>, reserved_tokens: usize) -> Self {
+        let bpe = tiktoken_rs::tokenizer::get_tokenizer(model).map_or_else(
+            tiktoken_rs::cl100k_base_singleton,
+            |tokenizer| {
+                tiktoken_rs::bpe_for_tokenizer(tokenizer)
+                    .unwrap_or_else(|_| tiktoken_rs::cl100k_base_singleton())
+            },
+        );
+        let context_window = context_window
+            .or_else(|| tiktoken_rs::model::get_context_size(model))
+            .unwrap_or(DEFAULT_CONTEXT_WINDOW);
+        Self {
+            bpe,
+            context_window,
+            reserved_tokens,
+        }
+    }
+
+    /// Number of tokens available to the messages of a request.
+    #[inline]
+    pub fn available(&self) -> usize {
+        self.context_window
+            .saturating_sub(self.reserved_tokens)
+            .saturating_sub(TOKENS_PER_REPLY)
+    }
+
+    /// Budget left for the chat when `messages` are always sent along.
+    #[inline]
+    pub fn without(&self, messages: &[ChatCompletionRequestMessage]) -> Self {
+        let reserved_tokens = messages
+            .iter()
+            .map(|message| self.count(message))
+            .sum::<usize>()
+            + self.reserved_tokens;
+        Self {
+            reserved_tokens,
+            ..*self
+        }
+    }
+
+    /// Number of tokens a single message takes in a request.
+    #[inline]
+    pub fn count(&self, message: &ChatCompletionRequestMessage) -> usize {
+        let name_tokens = message
+            .name
+            .as_deref()
+            .map_or(0, |name| TOKENS_PER_NAME + self.bpe.count_ordinary(name));
+        TOKENS_PER_MESSAGE
+            + self.bpe.count_ordinary(&message.role.to_string())
+            + self.bpe.count_ordinary(&message.content)
+            + name_tokens
+    }
+}
+
+/// Drop the oldest exchanges until the chat fits the token budget.
+///
+/// An exchange is a message together with the replies of the assistant to it,
+/// so that no reply is kept without what it answers.
+/// Pinned messages and the last exchange are never dropped.
+/// Dropped messages are returned separately,
+/// in the same way as [`ContextStrategy::split`](crate::context::ContextStrategy::split).
+#[inline]
+pub fn fit_chat_to_budget(
+    chat: Vec<ChatEntry>,
+    budget: &TokenBudget,
+) -> eyre::Result<(Vec<ChatEntry>, Option<Vec<ChatEntry>>)> {
+    let counts: Vec<_> = chat
+        .iter()
+        .map(|entry| budget.count(&entry.message))
+        .collect();
+    let available = budget.available();
+
+    let mut total: usize = counts.iter().sum();
+    let mut dropped = vec![false; chat.len()];
+    let mut start = 0;
+    while total > available {
+        let Some(end) = (start + 1..chat.len()).find(|&n| chat[n].message.role != Role::Assistant)
+        else {
+            break;
+        };
+        for n in start..end {
+            if !chat[n].metadata.pinned {
+                total -= counts[n];
+                dropped[n] = true;
+            }
+        }
+        start = end;
+    }
+    let n_pinned = chat
+        .iter()
+        .zip(&dropped)
+        .filter(|(entry, &dropped)| !dropped && entry.metadata.pinned)
+        .count();
+    eyre::ensure!(
+        total <= available,
+        "message is too long for the context window (needs {total} tokens{}, but only {available} \
+         are available)",
+        if n_pinned > 0 {
+            format!(" together with {n_pinned} pinned messages")
+        } else {
+            String::new()
+        }
+    );
+
+    if !dropped.contains(&true) {
+        return Ok((chat, None));
+    }
+
+    let (outdated_chat, current_chat): (Vec<_>, Vec<_>) = chat
+        .into_iter()
+        .zip(dropped)
+        .partition(|&(_, dropped)| dropped);
+    let current_chat = current_chat.into_iter().map(|(entry, _)| entry).collect();
+    let outdated_chat = outdated_chat.into_iter().map(|(entry, _)| entry).collect();
+
+    Ok((current_chat, Some(outdated_chat)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::test_entry;
+
+    fn entry(content: &str) -> ChatEntry {
+        test_entry(Role::User, content, vec![1.0])
+    }
+
+    #[test]
+    fn budget_counts_tokens() {
+        let budget = TokenBudget::for_model("gpt-4", None, 0);
+        assert_eq!(budget.available(), 8192 - TOKENS_PER_REPLY);
+        // "user" and "hello world" are one and two tokens, respectively.
+        assert_eq!(budget.count(&entry("hello world").message), 6);
+
+        let budget = TokenBudget::for_model("some-local-model", Some(2048), 512);
+        assert_eq!(budget.available(), 2048 - 512 - TOKENS_PER_REPLY);
+        assert_eq!(
+            budget.without(&[entry("hello world").message]).available(),
+            2048 - 512 - 6 - TOKENS_PER_REPLY
+        );
+    }
+
+    #[test]
+    fn fit_chat_to_budget_drops_oldest_messages() -> eyre::Result<()> {
+        let chat: Vec<_> = ["one two", "three", "four", "five six seven"]
+            .into_iter()
+            .map(entry)
+            .collect();
+        let budget = TokenBudget::for_model("gpt-4", Some(100), 0);
+        let (current_chat, outdated_chat) = fit_chat_to_budget(chat.clone(), &budget)?;
+        assert_eq!(current_chat.len(), 4);
+        assert!(outdated_chat.is_none());
+
+        let budget = TokenBudget::for_model("gpt-4", Some(19), 0);
+        let (current_chat, outdated_chat) = fit_chat_to_budget(chat.clone(), &budget)?;
+        assert_eq!(current_chat.len(), 2);
+        assert_eq!(current_chat[0].message.content, "four");
+        assert_eq!(outdated_chat.map(|chat| chat.len()), Some(2));
+
+        let budget = TokenBudget::for_model("gpt-4", Some(5), 0);
+        assert!(fit_chat_to_budget(chat.clone(), &budget).is_err());
+
+        let mut chat = chat;
+        chat[1].metadata.pinned = true;
+        let budget = TokenBudget::for_model("gpt-4", Some(19), 0);
+        let (current_chat, outdated_chat) = fit_chat_to_budget(chat, &budget)?;
+        assert_eq!(
+            current_chat
+                .iter()
+                .map(|entry| entry.message.content.as_str())
+                .collect::<Vec<_>>(),
+            ["three", "five six seven"]
+        );
+        assert_eq!(outdated_chat.map(|chat| chat.len()), Some(2));
+
+        // Dropping "one" alone would be enough,
+        // but would leave its answer without the question.
+        let mut chat: Vec<_> = ["one", "two", "three", "four", "five"]
+            .into_iter()
+            .map(entry)
+            .collect();
+        chat[1].message.role = Role::Assistant;
+        chat[3].message.role = Role::Assistant;
+        let budget = TokenBudget::for_model("gpt-4", Some(24), 0);
+        let (current_chat, outdated_chat) = fit_chat_to_budget(chat.clone(), &budget)?;
+        assert_eq!(current_chat[0].message.content, "three");
+        assert_eq!(current_chat[0].message.role, Role::User);
+        assert_eq!(outdated_chat.map(|chat| chat.len()), Some(2));
+
+        // The last exchange is kept whole.
+        let budget = TokenBudget::for_model("gpt-4", Some(12), 0);
+        assert!(fit_chat_to_budget(chat[2..4].to_vec(), &budget).is_err());
+        Ok(())
+    }
+}